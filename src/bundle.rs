@@ -0,0 +1,112 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// A self-contained archive is the tsh binary with a signed archive and this
+// trailer glued onto the end of it. See doc/formats.txt.
+const MAGIC: &'static [u8; 8] = b"TSHBNDL1";
+const TRAILER_LEN: usize = 8 + 8;
+
+// There's deliberately no key in here: whoever can swap the archive could
+// swap that too, so the key to verify against has to come from elsewhere.
+pub struct Bundle {
+    pub archive: Vec<u8>,
+}
+
+struct Trailer {
+    archive_len: u64,
+}
+
+fn read_trailer(bytes: &[u8]) -> Option<Trailer> {
+    if bytes.len() != TRAILER_LEN || &bytes[TRAILER_LEN - 8..] != MAGIC {
+        return None;
+    }
+    io::Cursor::new(&bytes[..8]).read_u64::<BigEndian>().ok()
+        .map(|archive_len| Trailer{archive_len: archive_len})
+}
+
+// Strip any bundle that's already attached so bundles of bundles don't nest.
+pub fn runtime(exe: &[u8]) -> &[u8] {
+    if exe.len() < TRAILER_LEN {
+        return exe;
+    }
+    match read_trailer(&exe[exe.len() - TRAILER_LEN..]) {
+        Some(trailer) => {
+            let attached = trailer.archive_len as usize + TRAILER_LEN;
+            if attached <= exe.len() {
+                &exe[..exe.len() - attached]
+            } else {
+                exe
+            }
+        },
+        None => exe
+    }
+}
+
+pub fn write<W: Write>(out: &mut W, runtime: &[u8], archive: &[u8]) -> io::Result<()> {
+    try!(out.write_all(runtime));
+    try!(out.write_all(archive));
+    try!(out.write_u64::<BigEndian>(archive.len() as u64));
+    try!(out.write_all(MAGIC));
+    Ok(())
+}
+
+pub fn read<R: Read + Seek>(input: &mut R) -> io::Result<Option<Bundle>> {
+    let len = try!(input.seek(SeekFrom::End(0)));
+    if len < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut trailer_bytes = [0u8; TRAILER_LEN];
+    try!(input.seek(SeekFrom::End(-(TRAILER_LEN as i64))));
+    try!(input.read_exact(&mut trailer_bytes));
+    let trailer = match read_trailer(&trailer_bytes) {
+        Some(v) => v,
+        None => return Ok(None)
+    };
+
+    if trailer.archive_len > len - TRAILER_LEN as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid Bundle Trailer"));
+    }
+
+    let mut archive = vec![0u8; trailer.archive_len as usize];
+    try!(input.seek(SeekFrom::End(-(TRAILER_LEN as i64) - trailer.archive_len as i64)));
+    try!(input.read_exact(&mut archive));
+
+    Ok(Some(Bundle{archive: archive}))
+}
+
+pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Option<Bundle>> {
+    read(&mut try!(File::open(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, runtime, write};
+    use std::io;
+
+    #[test]
+    fn roundtrip() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, b"runtime", b"archive").unwrap();
+
+        let bundle = read(&mut io::Cursor::new(&bytes)).unwrap().unwrap();
+        assert_eq!(bundle.archive, b"archive");
+        assert_eq!(runtime(&bytes), b"runtime");
+
+        // bundling a bundle replaces the archive instead of nesting it
+        let mut rebundled = Vec::new();
+        write(&mut rebundled, runtime(&bytes), b"another archive").unwrap();
+        assert_eq!(runtime(&rebundled), b"runtime");
+        assert_eq!(read(&mut io::Cursor::new(&rebundled)).unwrap().unwrap().archive, b"another archive");
+    }
+
+    #[test]
+    fn not_a_bundle() {
+        assert!(read(&mut io::Cursor::new(b"just a regular binary")).unwrap().is_none());
+        assert!(read(&mut io::Cursor::new(b"")).unwrap().is_none());
+        assert_eq!(runtime(b"just a regular binary"), b"just a regular binary");
+    }
+}
@@ -6,16 +6,28 @@ use std::path::{Path, PathBuf};
 use rustc_serialize::base64;
 use rustc_serialize::base64::ToBase64;
 
+use std::collections::HashSet;
+use std::env;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::iter;
+use std::os::unix::fs::PermissionsExt;
 
 use tar;
 use toml;
 use cast;
 
+use bundle;
+use facts::Facts;
+use lockfile;
+use lockfile::Lockfile;
+use metadata;
 use seedfile;
 use runlist;
+use template;
+use resolve::EnvConflicts;
 
 use walkdir::{DirEntry, WalkDir, WalkDirIterator};
 
@@ -89,15 +101,30 @@ impl runlist::RunList {
         try!(write_to_archive(&mut builder, &basedir_with_slash, PathBuf::from(&self.basedir).join("roles")));
 
 
-        // write the role file for all roles
+        // write the role file for all roles, and the roles they include
+        let mut role_paths = HashSet::new();
         for role in self.roles.iter() {
-            try!(write_to_archive(&mut builder, &basedir_with_slash, &role.path));
+            for path in Some(&role.path).into_iter().chain(role.includes.iter()) {
+                if role_paths.insert(path) {
+                    try!(write_to_archive(&mut builder, &basedir_with_slash, path));
+                }
+            }
         }
 
         // now write all (non hidden) files for all deps of this runlist
         match self.sort_dependencies() {
             Ok(deps) => {
-                for dep in deps {
+                let mut parents = HashSet::new();
+                let mut dirs = HashSet::new();
+                // a package that runs once per role is only archived once
+                for dep in deps.into_iter().filter(|dep| dirs.insert(dep.dir.clone())) {
+                    // versioned packages live in name/version/, so name/ has to come first
+                    if let Some(parent) = dep.dir.parent() {
+                        if parent != self.basedir.as_path() && parents.insert(parent.to_path_buf()) {
+                            try!(write_to_archive(&mut builder, &basedir_with_slash, parent));
+                        }
+                    }
+
                     let walker = WalkDir::new(dep.dir).into_iter();
                     for entry in walker.filter_entry(|e| !is_hidden(e)) {
                         let entry = try!(entry);
@@ -118,8 +145,386 @@ impl runlist::RunList {
     }
 }
 
-pub fn main<W: Write>(out: &mut W, basedir_string: String, output_string: String, seedfile_string: String, roles: Vec<String>) -> i32 {
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+fn is_shell_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => false
+    }
+}
+
+// Octal escapes in a printf(1) format string get every byte through
+// untouched, so binary files survive as well as text.
+fn printf_format(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() + 2);
+    s.push('\'');
+    for (i, &b) in bytes.iter().enumerate() {
+        match b {
+            // a leading dash would be read as an option by some printfs
+            b'-' if i == 0 => s.push_str("\\055"),
+            b'\\' => s.push_str("\\\\"),
+            b'%' => s.push_str("%%"),
+            b'\'' => s.push_str("\\047"),
+            0x20..=0x7e => s.push(b as char),
+            _ => s.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    s.push('\'');
+    s
+}
+
+fn relative_to<'a>(path: &'a Path, basedir: &Path) -> io::Result<&'a Path> {
+    path.strip_prefix(basedir).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} isn't inside {}", path.display(), basedir.display()))
+    })
+}
+
+// Recreate a file, one printf per line so the script stays readable.
+fn printf_lines(contents: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < contents.len() {
+        let end = match contents[start..].iter().position(|&b| b == b'\n') {
+            Some(i) => start + i + 1,
+            None => contents.len()
+        };
+        lines.push(format!("printf {}", printf_format(&contents[start..end])));
+        start = end;
+    }
+    lines
+}
+
+fn write_script_file<W: Write>(out: &mut W, name: &str, contents: &[u8], mode: u32) -> io::Result<()> {
+    if contents.is_empty() {
+        try!(writeln!(out, ": > \"$TSH_DIR\"/{} || exit 1", name));
+    } else {
+        try!(writeln!(out, "{{"));
+        for line in printf_lines(contents) {
+            try!(writeln!(out, "{}", line));
+        }
+        try!(writeln!(out, "}} > \"$TSH_DIR\"/{} || exit 1", name));
+    }
+    writeln!(out, "chmod {:o} \"$TSH_DIR\"/{}", mode, name)
+}
+
+// The commands that render a template into `name` when the script runs, so
+// it gets the host's facts like it would from `tsh run`.
+fn script_template(template: &Path, name: &str, mode: u32) -> io::Result<Vec<String>> {
+    let source = match template::read_file(template) {
+        Ok(v) => v,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    };
+    let pieces = match template::parse(template, &source) {
+        Ok(v) => v,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    };
+
+    let mut lines = Vec::new();
+    if pieces.is_empty() {
+        lines.push(format!(": > \"$TSH_DIR\"/{} || exit 1", name));
+    } else {
+        lines.push("{".to_string());
+        for piece in pieces {
+            match piece {
+                template::Piece::Text(text) => lines.extend(printf_lines(text.as_bytes())),
+                template::Piece::Var(var) => lines.push(format!("printf '%s' \"${}\"", var))
+            }
+        }
+        lines.push(format!("}} > \"$TSH_DIR\"/{} || exit 1", name));
+    }
+    lines.push(format!("chmod {:o} \"$TSH_DIR\"/{}", mode, name));
+    Ok(lines)
+}
+
+impl runlist::RunList {
+    // A POSIX shell script that does what `tsh run` would do with this
+    // runlist's archive, for hosts that can't run tsh itself.
+    pub fn write_script<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let deps = match self.sort_dependencies() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e.message()));
+            }
+        };
+
+        // there are no facts to check them against
+        match self.conditions() {
+            Ok(ref conditions) if ! conditions.is_empty() => {
+                let c = &conditions[0];
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} depends on {} when {}, but sh scripts can't check conditions", c.from, c.name, c.condition)));
+            },
+            Ok(_) => {},
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.message()))
+        }
+
+        let roles = self.roles.iter().map(|r| r.name.clone()).collect::<Vec<String>>();
+        try!(writeln!(out, "#!/bin/sh"));
+        try!(writeln!(out, "# Turboshell run plan for roles: {}", roles.join(", ")));
+        try!(writeln!(out, "# Generated by `tsh compile --format sh`. This script isn't signed."));
+        try!(writeln!(out, ""));
+
+        if let Some(expires) = self.expires {
+            try!(writeln!(out, "# expires {}", metadata::format_timestamp(expires)));
+            try!(writeln!(out, "if [ \"$(date +%s)\" -ge {} ]; then", expires));
+            try!(writeln!(out, "    echo 'error running archive: archive has expired' >&2"));
+            try!(writeln!(out, "    exit 1"));
+            try!(writeln!(out, "fi"));
+            try!(writeln!(out, ""));
+        }
+
+        // the host's facts, which `tsh run` gives every package
+        try!(writeln!(out, "for TSH_OS_RELEASE in /etc/os-release /usr/lib/os-release /dev/null; do"));
+        try!(writeln!(out, "    [ -r \"$TSH_OS_RELEASE\" ] && break"));
+        try!(writeln!(out, "done"));
+        try!(writeln!(out, "TSH_FACT_OS=$(. \"$TSH_OS_RELEASE\"; printf '%s' \"$ID\")"));
+        try!(writeln!(out, "TSH_FACT_OS_FAMILY=$(. \"$TSH_OS_RELEASE\"; set -- $ID_LIKE; printf '%s' \"${{1:-$ID}}\")"));
+        try!(writeln!(out, "TSH_FACT_OS_VERSION=$(. \"$TSH_OS_RELEASE\"; printf '%s' \"$VERSION_ID\")"));
+        try!(writeln!(out, "TSH_FACT_OS_NAME=$(. \"$TSH_OS_RELEASE\"; printf '%s' \"$PRETTY_NAME\")"));
+        try!(writeln!(out, "TSH_FACT_KERNEL=$(uname -r)"));
+        try!(writeln!(out, "TSH_FACT_ARCH=$(uname -m)"));
+        try!(writeln!(out, "TSH_FACT_HOSTNAME=$(uname -n)"));
+        try!(writeln!(out, "TSH_FACT_CPUS=$(getconf _NPROCESSORS_ONLN 2>/dev/null || echo 0)"));
+        try!(writeln!(out, "TSH_FACT_MEMORY=$(awk '/^MemTotal:/ {{ printf \"%.0f\", $2 * 1024 }}' /proc/meminfo 2>/dev/null)"));
+        try!(writeln!(out, "TSH_FACT_MEMORY=${{TSH_FACT_MEMORY:-0}}"));
+        try!(writeln!(out, "TSH_FACT_IP_ADDRESSES=$(ip -o addr show 2>/dev/null | awk '$2 != \"lo\" {{ sub(\"/.*\", \"\", $4); print $4 }}' | LC_ALL=C sort -u | tr '\\n' ' ')"));
+        try!(writeln!(out, "TSH_FACT_IP_ADDRESSES=${{TSH_FACT_IP_ADDRESSES% }}"));
+        try!(writeln!(out, "export TSH_FACT_OS TSH_FACT_OS_FAMILY TSH_FACT_OS_VERSION TSH_FACT_OS_NAME TSH_FACT_KERNEL"));
+        try!(writeln!(out, "export TSH_FACT_ARCH TSH_FACT_HOSTNAME TSH_FACT_CPUS TSH_FACT_MEMORY TSH_FACT_IP_ADDRESSES"));
+        try!(writeln!(out, ""));
+
+        try!(writeln!(out, "TSH_DIR=$(mktemp -d \"${{TMPDIR:-/tmp}}/turboshell.XXXXXX\") || exit 1"));
+        try!(writeln!(out, "trap 'rm -rf \"$TSH_DIR\"' EXIT"));
+        try!(writeln!(out, "trap 'exit 130' INT TERM"));
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "TSH_SUMMARY=''"));
+        try!(writeln!(out, "TSH_CHANGED=0"));
+        try!(writeln!(out, "TSH_UNCHANGED=0"));
+        try!(writeln!(out, "TSH_FAILED=0"));
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "tsh_status() {{"));
+        try!(writeln!(out, "    TSH_SUMMARY=\"$TSH_SUMMARY  $1: $2"));
+        try!(writeln!(out, "\""));
+        try!(writeln!(out, "    case $2 in"));
+        try!(writeln!(out, "        changed) TSH_CHANGED=$((TSH_CHANGED + 1)) ;;"));
+        try!(writeln!(out, "        unchanged) TSH_UNCHANGED=$((TSH_UNCHANGED + 1)) ;;"));
+        try!(writeln!(out, "        failed) TSH_FAILED=$((TSH_FAILED + 1)) ;;"));
+        try!(writeln!(out, "    esac"));
+        try!(writeln!(out, "}}"));
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "tsh_summary() {{"));
+        try!(writeln!(out, "    printf '\\nSummary:\\n%s' \"$TSH_SUMMARY\""));
+        try!(writeln!(out, "    printf '%s changed, %s unchanged, %s failed\\n' \"$TSH_CHANGED\" \"$TSH_UNCHANGED\" \"$TSH_FAILED\""));
+        try!(writeln!(out, "}}"));
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "tsh_fail() {{"));
+        try!(writeln!(out, "    tsh_status \"$1\" failed"));
+        try!(writeln!(out, "    tsh_summary"));
+        try!(writeln!(out, "    echo \"error running archive: $2 returned exit status: $3\" >&2"));
+        try!(writeln!(out, "    exit 1"));
+        try!(writeln!(out, "}}"));
+
+        // lay out every (non hidden) file of every package, same as the archive
+        let mut dir_modes = Vec::new();
+        let mut dirs = HashSet::new();
+        for dep in deps.iter() {
+            if ! dirs.insert(&dep.dir) {
+                continue;
+            }
+            try!(writeln!(out, "\n# package {} {}", dep.name, dep.version));
+            // like `unpack::explode`, never hand out setuid, setgid or sticky bits
+            let walker = WalkDir::new(&dep.dir).into_iter();
+            for entry in walker.filter_entry(|e| !is_hidden(e)) {
+                let entry = try!(entry);
+                let name = shell_quote(try!(relative_to(entry.path(), &self.basedir)).to_str().unwrap());
+                let metadata = try!(entry.metadata());
+
+                if metadata.is_dir() {
+                    try!(writeln!(out, "mkdir -p \"$TSH_DIR\"/{} || exit 1", name));
+                    dir_modes.push((name, metadata.mode() & 0o777));
+                } else {
+                    let mut contents = Vec::new();
+                    try!(try!(File::open(entry.path())).read_to_end(&mut contents));
+                    try!(write_script_file(out, &name, &contents, metadata.mode() & 0o777));
+                }
+            }
+
+            // templates are rendered before each run, but need somewhere to go
+            for output in dep.templates.values() {
+                if let Some(parent) = try!(relative_to(output, &self.basedir)).parent() {
+                    try!(writeln!(out, "mkdir -p \"$TSH_DIR\"/{} || exit 1", shell_quote(parent.to_str().unwrap())));
+                }
+            }
+        }
+
+        // directory modes last in case one of them isn't writable
+        try!(writeln!(out, ""));
+        for (name, mode) in dir_modes {
+            try!(writeln!(out, "chmod {:o} \"$TSH_DIR\"/{}", mode, name));
+        }
+
+        // then run them in order, like RunList::run
+        try!(writeln!(out, "\nprintf '%s\\n' {}", shell_quote(&format!("Running: {}", deps.iter().map(|p| p.name.clone()).collect::<Vec<String>>().join(", ")))));
+        for dep in deps.iter() {
+            let text = format!("##    Running Package {}    ##", dep.name);
+            let line = iter::repeat("#").take(text.len()).collect::<String>();
+            let dir = shell_quote(try!(relative_to(&dep.dir, &self.basedir)).to_str().unwrap());
+            let main = shell_quote(try!(relative_to(&dep.main, &self.basedir)).to_str().unwrap());
+
+            try!(writeln!(out, ""));
+            try!(writeln!(out, "printf '\\n%s\\n%s\\n%s\\n\\n' {} {} {}", shell_quote(&line), shell_quote(&text), shell_quote(&line)));
+            // the subshell that runs main, or check first when there is one
+            let mut env = Vec::new();
+            for (k, v) in dep.env.iter() {
+                if ! is_shell_name(k) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("package {}: env var \"{}\" can't be set from a shell script", dep.name, k)));
+                }
+                env.push(format!("{}={}; export {}", k, shell_quote(v), k));
+            }
+            let name = shell_quote(&dep.name);
+
+            if ! dep.templates.is_empty() {
+                try!(writeln!(out, "("));
+                for line in env.iter() {
+                    try!(writeln!(out, "    {}", line));
+                }
+                for (template, output) in dep.templates.iter() {
+                    let mode = try!(fs::metadata(template)).mode() & 0o777;
+                    let output = shell_quote(try!(relative_to(output, &self.basedir)).to_str().unwrap());
+                    for line in try!(script_template(template, &output, mode)) {
+                        try!(writeln!(out, "    {}", line));
+                    }
+                }
+                try!(writeln!(out, ") || tsh_fail {} {} $?", name, shell_quote(&format!("rendering the templates of {}", dep.name))));
+            }
+
+            if let Some(ref check) = dep.check {
+                let check = shell_quote(try!(relative_to(check, &self.basedir)).to_str().unwrap());
+                try!(writeln!(out, "if ("));
+                try!(writeln!(out, "    cd \"$TSH_DIR\"/{} || exit 1", dir));
+                for line in env.iter() {
+                    try!(writeln!(out, "    {}", line));
+                }
+                try!(writeln!(out, "    exec \"$TSH_DIR\"/{}", check));
+                try!(writeln!(out, "); then"));
+                try!(writeln!(out, "    printf '%s\\n' 'check passed, skipping main'"));
+                try!(writeln!(out, "    tsh_status {} unchanged", name));
+                try!(writeln!(out, "else"));
+                try!(writeln!(out, "    ("));
+                try!(writeln!(out, "        cd \"$TSH_DIR\"/{} || exit 1", dir));
+                for line in env.iter() {
+                    try!(writeln!(out, "        {}", line));
+                }
+                try!(writeln!(out, "        exec \"$TSH_DIR\"/{}", main));
+                try!(writeln!(out, "    ) || tsh_fail {} \"$TSH_DIR\"/{} $?", name, main));
+                try!(writeln!(out, "    tsh_status {} changed", name));
+                try!(writeln!(out, "fi"));
+            } else {
+                try!(writeln!(out, "("));
+                try!(writeln!(out, "    cd \"$TSH_DIR\"/{} || exit 1", dir));
+                for line in env.iter() {
+                    try!(writeln!(out, "    {}", line));
+                }
+                try!(writeln!(out, "    exec \"$TSH_DIR\"/{}", main));
+                try!(writeln!(out, ") || tsh_fail {} \"$TSH_DIR\"/{} $?", name, main));
+                try!(writeln!(out, "tsh_status {} changed", name));
+            }
+        }
+
+        try!(writeln!(out, "\ntsh_summary"));
+        Ok(())
+    }
+}
+
+// Durations look like "90s", "30m", "12h" or "7d". A bare number is seconds.
+fn parse_duration(s: &str) -> Option<i64> {
+    let (number, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 60 * 60),
+        Some('d') => (&s[..s.len() - 1], 60 * 60 * 24),
+        Some(_) => (s, 1),
+        None => return None
+    };
+
+    match number.parse::<i64>() {
+        Ok(n) if n > 0 => n.checked_mul(multiplier),
+        _ => None
+    }
+}
+
+fn read_runtime() -> io::Result<Vec<u8>> {
+    let mut exe = Vec::new();
+    try!(try!(File::open(try!(env::current_exe()))).read_to_end(&mut exe));
+    let len = bundle::runtime(&exe).len();
+    exe.truncate(len);
+    Ok(exe)
+}
+
+pub enum Format {
+    // a signed archive for `tsh run`
+    Archive,
+    // the tsh binary with a signed archive attached, see bundle.rs
+    SelfContained,
+    // a POSIX shell script that doesn't need tsh at all
+    Shell,
+}
+
+// What `tsh compile` was asked to do. Strings are as given on the command
+// line, and empty when they weren't.
+pub struct Options {
+    pub basedir: String,
+    // stdout when empty
+    pub output: String,
+    pub seedfile: String,
+    pub expires_in: String,
+    pub format: Format,
+    // fail instead of updating turboshell.lock
+    pub locked: bool,
+    pub env_conflicts: EnvConflicts,
+    pub roles: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options{basedir: "./".to_string(),
+                output: "".to_string(),
+                seedfile: "".to_string(),
+                expires_in: "".to_string(),
+                format: Format::Archive,
+                locked: false,
+                env_conflicts: EnvConflicts::Merge,
+                roles: Vec::new()}
+    }
+}
+
+pub fn main<W: Write>(out: &mut W, options: Options) -> i32 {
     let mut stderr = io::stderr();
+    let Options{basedir: basedir_string,
+                output: output_string,
+                seedfile: seedfile_string,
+                expires_in: expires_in_string,
+                format,
+                locked,
+                env_conflicts,
+                roles} = options;
+
+    let expires_in = if expires_in_string != "" {
+        match parse_duration(&expires_in_string) {
+            Some(v) => Some(v),
+            None => {
+                writeln!(&mut stderr, "Invalid duration {}: expected something like 90s, 30m, 12h or 7d", expires_in_string).unwrap();
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
 
     let basedir = match PathBuf::from(&basedir_string).canonicalize() {
         Ok(value) => value,
@@ -148,13 +553,125 @@ pub fn main<W: Write>(out: &mut W, basedir_string: String, output_string: String
         seedfile.keypair()
     };
 
-    let runlist = match runlist::RunList::from_roles(&basedir, &roles) {
+    let mut runlist = match runlist::RunList::from_roles(&basedir, &roles) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+    runlist.handle_env_conflicts(env_conflicts);
+
+    // only what the roles use is loaded, so say what wasn't
+    for warning in runlist.warnings() {
+        writeln!(&mut stderr, "warning: {}", warning).unwrap();
+    }
+    if ! runlist.skipped().is_empty() {
+        let names = runlist.skipped().iter()
+            .map(|dir| dir.file_name().unwrap_or_default().to_string_lossy().into_owned())
+            .collect::<Vec<String>>();
+        writeln!(&mut stderr, "skipped {}, which the roles don't use", names.join(", ")).unwrap();
+    }
+    //////// HONOR THE LOCKFILE /////////
+    let lockfile_path = basedir.join(lockfile::FILENAME);
+    let lockfile = match Lockfile::from_path(&lockfile_path) {
         Ok(v) => v,
         Err(e) => {
             writeln!(&mut stderr, "{}", e).unwrap();
             return 1;
         }
     };
+    if let Some(ref lockfile) = lockfile {
+        runlist.prefer_versions(lockfile.versions());
+    }
+
+    let exes = match runlist.sort_dependencies() {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+
+    // with `merge` a role's value can lose out to another's without anyone
+    // noticing; `error` has failed already and `per-role` is what they asked for
+    if env_conflicts == EnvConflicts::Merge {
+        match runlist.env_conflicts() {
+            Ok(conflicts) => for conflict in conflicts {
+                writeln!(&mut stderr, "warning: {}; role {} wins", conflict, conflict.values[0].0).unwrap();
+            },
+            Err(e) => {
+                writeln!(&mut stderr, "{}", e).unwrap();
+                return 1;
+            }
+        }
+    }
+
+    // templates are rendered at run time, so catch mistakes in them now. The
+    // host's facts aren't known yet, but templates can use them.
+    for exe in exes.iter() {
+        let mut env = exe.env.clone();
+        env.extend(Facts::default().to_env());
+        for template in exe.templates.keys() {
+            if let Err(e) = template::render_file(template, &env) {
+                writeln!(&mut stderr, "{}", e).unwrap();
+                return 1;
+            }
+        }
+    }
+
+    let resolved = match Lockfile::from_executables(&exes) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "error hashing packages: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    if locked {
+        let differences = match lockfile {
+            Some(ref lockfile) => lockfile.differences(&resolved),
+            None => vec![format!("{} doesn't exist", lockfile_path.display())]
+        };
+        if ! differences.is_empty() {
+            for difference in differences {
+                writeln!(&mut stderr, "{}", difference).unwrap();
+            }
+            writeln!(&mut stderr, "{} is out of date and --locked was given", lockfile::FILENAME).unwrap();
+            return 1;
+        }
+    } else {
+        let mut updated = lockfile.clone().unwrap_or_else(Lockfile::new);
+        updated.merge(&resolved);
+        if lockfile.as_ref() != Some(&updated) {
+            if let Err(e) = File::create(&lockfile_path).and_then(|mut f| f.write_all(updated.to_string().as_bytes())) {
+                writeln!(&mut stderr, "Can't write {}: {}", lockfile_path.display(), e).unwrap();
+                return 1;
+            }
+        }
+    }
+
+    let now = metadata::now();
+    runlist.created = Some(now);
+    runlist.expires = expires_in.map(|seconds| now + seconds);
+
+    let mut output: Box<Write> = if output_string != "" {
+        Box::new(File::create(&output_string).unwrap())
+    } else {
+        Box::new(out)
+    };
+
+    if let Format::Shell = format {
+        if let Err(e) = runlist.write_script(&mut output) {
+            writeln!(&mut stderr, "error writing shell script: {}", e).unwrap();
+            return 1;
+        }
+
+        if output_string != "" {
+            fs::set_permissions(&output_string, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        return 0;
+    }
 
     //////// CREATE THE TARBALL /////////
     let mut tarball_bytes = Vec::new();
@@ -172,17 +689,51 @@ pub fn main<W: Write>(out: &mut W, basedir_string: String, output_string: String
 
 
 
+    let mut archive_bytes = Vec::with_capacity(8 + signature.len() + tarball_bytes.len());
+    archive_bytes.extend_from_slice(b"TURBOv01");
+    archive_bytes.extend_from_slice(signature.as_bytes());
+    archive_bytes.extend_from_slice(&tarball_bytes);
+
+
+
     //////// WRITE OUT THE ARCHIVE //////////
-    let mut output: Box<Write> = if output_string != "" {
-        Box::new(File::create(output_string).unwrap())
+    if let Format::SelfContained = format {
+        let runtime = match read_runtime() {
+            Ok(v) => v,
+            Err(e) => {
+                writeln!(&mut stderr, "Can't read the tsh binary to bundle: {}", e).unwrap();
+                return 1;
+            }
+        };
+        bundle::write(&mut output, &runtime, &archive_bytes).unwrap();
+
+        if output_string != "" {
+            fs::set_permissions(&output_string, fs::Permissions::from_mode(0o755)).unwrap();
+        }
     } else {
-        Box::new(out)
-    };
-    // let mut output = File::create(args.flag_output).unwrap();
-       
-    output.write_all(b"TURBOv01").unwrap();
-    output.write_all(signature.as_bytes()).unwrap();
-    output.write_all(&tarball_bytes).unwrap();
+        output.write_all(&archive_bytes).unwrap();
+    }
 
     return 0;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("12h"), Some(12 * 60 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("0d"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("7w"), None);
+        assert_eq!(parse_duration("forever"), None);
+    }
+}
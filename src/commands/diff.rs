@@ -0,0 +1,241 @@
+use rustc_serialize::base64::FromBase64;
+use sodiumoxide::crypto::sign;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use tempdir;
+
+use runlist;
+use textdiff;
+use unpack;
+
+// Everything about one archive that we compare.
+struct Snapshot {
+    roles: Vec<String>,
+    packages: BTreeMap<String, String>,
+    edges: BTreeSet<(String, String)>,
+    files: BTreeMap<PathBuf, FileEntry>,
+}
+
+#[derive(PartialEq)]
+enum FileEntry {
+    Directory(u32),
+    File(u32, Vec<u8>),
+}
+
+fn read_files<P: AsRef<Path>>(basedir: P) -> io::Result<BTreeMap<PathBuf, FileEntry>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(basedir.as_ref()).min_depth(1) {
+        let entry = try!(entry);
+        let name = entry.path().strip_prefix(basedir.as_ref()).unwrap().to_path_buf();
+
+        // archive.toml is regenerated by every compile; roles are compared separately
+        if name == Path::new("archive.toml") {
+            continue;
+        }
+
+        let metadata = try!(entry.metadata());
+        if metadata.is_dir() {
+            files.insert(name, FileEntry::Directory(metadata.mode() & 0o7777));
+        } else {
+            let mut contents = Vec::new();
+            try!(try!(File::open(entry.path())).read_to_end(&mut contents));
+            files.insert(name, FileEntry::File(metadata.mode() & 0o7777, contents));
+        }
+    }
+    Ok(files)
+}
+
+fn load(pubkey: &sign::PublicKey, archive_string: &str) -> Result<Snapshot, String> {
+    let mut input = match File::open(archive_string) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("{}: {}", e, archive_string))
+    };
+
+    let tarball_bytes = match unpack::unpack(&mut input, pubkey.clone()) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("{}: {}", archive_string, e))
+    };
+
+    // Create a place for the archive to be unpacked
+    let tempdir = match tempdir::TempDir::new("turboshell") {
+        Ok(value) => value,
+        Err(e) => return Err(format!("error creating temp dir: {}", e))
+    };
+    let basedir = match tempdir.path().canonicalize() {
+        Ok(value) => value,
+        Err(e) => return Err(format!("Can't canonicalize temp dir: {}", e))
+    };
+
+    if let Err(e) = unpack::explode(tarball_bytes.as_slice(), &basedir) {
+        return Err(format!("{}: error unpacking archive: {}", archive_string, e));
+    }
+
+    let runlist = match runlist::RunList::from_archive(&basedir) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("{}: error reading archive: {}", archive_string, e))
+    };
+    let executables = match runlist.sort_dependencies() {
+        Ok(v) => v,
+        Err(e) => return Err(format!("{}: error reading archive: {}", archive_string, e))
+    };
+
+    let mut edges = BTreeSet::new();
+    for role in runlist.roles.iter() {
+        for package in role.dependencies() {
+            edges.insert((format!("role {}", role.name), package.name.clone()));
+        }
+    }
+    for exe in executables.iter() {
+        for dep in exe.dependencies.iter() {
+            edges.insert((exe.name.clone(), dep.clone()));
+        }
+    }
+
+    let files = match read_files(&basedir) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("{}: error reading archive: {}", archive_string, e))
+    };
+
+    Ok(Snapshot{ roles: runlist.roles.iter().map(|r| r.name.clone()).collect(),
+                 packages: executables.iter().map(|exe| (exe.name.clone(), exe.version.clone())).collect(),
+                 edges: edges,
+                 files: files })
+}
+
+fn is_text(bytes: &[u8]) -> Option<&str> {
+    if bytes.contains(&0) {
+        return None;
+    }
+    ::std::str::from_utf8(bytes).ok()
+}
+
+fn write_file_diff<W: Write>(out: &mut W, name: &Path, old: Option<&FileEntry>, new: Option<&FileEntry>) {
+    let old_name = format!("a/{}", name.display());
+    let new_name = format!("b/{}", name.display());
+
+    match (old, new) {
+        (None, Some(&FileEntry::Directory(_))) => writeln!(out, "  + {}/", name.display()).unwrap(),
+        (Some(&FileEntry::Directory(_)), None) => writeln!(out, "  - {}/", name.display()).unwrap(),
+        (Some(&FileEntry::Directory(old_mode)), Some(&FileEntry::Directory(new_mode))) => {
+            writeln!(out, "  M {}/ (mode {:o} -> {:o})", name.display(), old_mode, new_mode).unwrap()
+        },
+        _ => {
+            let (old_mode, old_bytes): (Option<u32>, &[u8]) = match old {
+                Some(&FileEntry::File(mode, ref bytes)) => (Some(mode), bytes),
+                _ => (None, &[])
+            };
+            let (new_mode, new_bytes): (Option<u32>, &[u8]) = match new {
+                Some(&FileEntry::File(mode, ref bytes)) => (Some(mode), bytes),
+                _ => (None, &[])
+            };
+
+            match (old_mode, new_mode) {
+                (None, _) => writeln!(out, "  + {}", name.display()).unwrap(),
+                (_, None) => writeln!(out, "  - {}", name.display()).unwrap(),
+                (Some(o), Some(n)) if o != n => {
+                    writeln!(out, "  M {} (mode {:o} -> {:o})", name.display(), o, n).unwrap()
+                },
+                _ => writeln!(out, "  M {}", name.display()).unwrap(),
+            }
+
+            if old_bytes != new_bytes {
+                match (is_text(old_bytes), is_text(new_bytes)) {
+                    (Some(o), Some(n)) => {
+                        if let Some(diff) = textdiff::unified(&old_name, &new_name, o, n, 3) {
+                            write!(out, "{}", diff).unwrap();
+                        }
+                    },
+                    _ => writeln!(out, "Binary files {} and {} differ", old_name, new_name).unwrap(),
+                }
+            }
+        }
+    }
+}
+
+fn write_diff<W: Write>(out: &mut W, old: &Snapshot, new: &Snapshot) {
+    writeln!(out, "Roles:").unwrap();
+    for role in new.roles.iter().filter(|r| !old.roles.contains(r)) {
+        writeln!(out, "  + {}", role).unwrap();
+    }
+    for role in old.roles.iter().filter(|r| !new.roles.contains(r)) {
+        writeln!(out, "  - {}", role).unwrap();
+    }
+
+    writeln!(out, "Packages:").unwrap();
+    for (name, version) in new.packages.iter() {
+        match old.packages.get(name) {
+            None => writeln!(out, "  + {} {}", name, version).unwrap(),
+            Some(old_version) if old_version != version => {
+                writeln!(out, "  ~ {} {} -> {}", name, old_version, version).unwrap()
+            },
+            _ => {}
+        }
+    }
+    for (name, version) in old.packages.iter().filter(|&(name, _)| !new.packages.contains_key(name)) {
+        writeln!(out, "  - {} {}", name, version).unwrap();
+    }
+
+    writeln!(out, "Dependencies:").unwrap();
+    for &(ref from, ref to) in new.edges.difference(&old.edges) {
+        writeln!(out, "  + {} -> {}", from, to).unwrap();
+    }
+    for &(ref from, ref to) in old.edges.difference(&new.edges) {
+        writeln!(out, "  - {} -> {}", from, to).unwrap();
+    }
+
+    writeln!(out, "Files:").unwrap();
+    let names = old.files.keys().chain(new.files.keys()).collect::<BTreeSet<&PathBuf>>();
+    for name in names {
+        let (o, n) = (old.files.get(name), new.files.get(name));
+        if o != n {
+            write_file_diff(out, name, o, n);
+        }
+    }
+}
+
+pub fn main<W: Write>(out: &mut W, pubkey_string: String, old_string: String, new_string: String) -> i32 {
+    let mut stderr = io::stderr();
+
+    // Create PublicKey
+    let pubkey_bytes = match pubkey_string.as_bytes().from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            writeln!(&mut stderr, "error decoding pubkey \"{}\" as base64", pubkey_string).unwrap();
+            return 1;
+        }
+    };
+    let pubkey = match sign::PublicKey::from_slice(&pubkey_bytes) {
+        Some(key) => key,
+        None => {
+            writeln!(&mut stderr, "error creating PublicKey").unwrap();
+            return 1;
+        }
+    };
+
+    // Verify and load both archives before reporting anything
+    let old = match load(&pubkey, &old_string) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+    let new = match load(&pubkey, &new_string) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+
+    writeln!(out, "--- {}\n+++ {}", old_string, new_string).unwrap();
+    write_diff(out, &old, &new);
+
+    return 0;
+}
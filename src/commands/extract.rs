@@ -0,0 +1,96 @@
+use rustc_serialize::base64::FromBase64;
+use sodiumoxide::crypto::sign;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use runlist;
+use unpack;
+
+pub fn main<W: Write>(out: &mut W, force: bool, pubkey_string: String, archive_string: String, directory_string: String) -> i32 {
+    let mut stderr = io::stderr();
+
+    let mut input: Box<Read> = if archive_string != "" {
+        match File::open(&archive_string) {
+            Ok(v) => Box::new(v),
+            Err(e) => {
+                writeln!(&mut stderr, "{}: {}", e, archive_string).unwrap();
+                return 1;
+            }
+        }
+    } else {
+        Box::new(io::stdin())
+    };
+
+    // Create PublicKey
+    let pubkey_bytes = match pubkey_string.as_bytes().from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            writeln!(&mut stderr, "error decoding pubkey \"{}\" as base64", pubkey_string).unwrap();
+            return 1;
+        }
+    };
+    let pubkey = match sign::PublicKey::from_slice(&pubkey_bytes) {
+        Some(key) => key,
+        None => {
+            writeln!(&mut stderr, "error creating PublicKey").unwrap();
+            return 1;
+        }
+    };
+
+    // Verify before anything touches the disk
+    let tarball_bytes = match unpack::unpack(&mut input, pubkey) {
+        Ok(value) => value,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+
+    // Make sure the destination exists and is empty unless we were told otherwise
+    let directory = PathBuf::from(&directory_string);
+    if directory.exists() {
+        let is_empty = match fs::read_dir(&directory) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(e) => {
+                writeln!(&mut stderr, "Can't read directory {}: {}", directory_string, e).unwrap();
+                return 1;
+            }
+        };
+        if ! is_empty && ! force {
+            writeln!(&mut stderr, "Directory {} isn't empty. Use --force to extract into it anyway.", directory_string).unwrap();
+            return 1;
+        }
+    } else if let Err(e) = fs::create_dir_all(&directory) {
+        writeln!(&mut stderr, "Can't create directory {}: {}", directory_string, e).unwrap();
+        return 1;
+    }
+
+    let basedir = match directory.canonicalize() {
+        Ok(value) => value,
+        Err(e) => {
+            writeln!(&mut stderr, "Can't find directory {}: {}", directory_string, e).unwrap();
+            return 1;
+        }
+    };
+
+    if let Err(e) = unpack::explode(tarball_bytes.as_slice(), &basedir) {
+        writeln!(&mut stderr, "error unpacking archive: {}", e).unwrap();
+        return 1;
+    }
+
+    // Check that what we left behind is something `tsh run -d` can use
+    let runlist = match runlist::RunList::from_archive(&basedir) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "error reading archive: {}", e).unwrap();
+            return 1;
+        }
+    };
+    let roles = runlist.roles.iter().map(|r| r.name.clone()).collect::<Vec<String>>();
+    writeln!(out, "Extracted roles {} into {}", roles.join(", "), basedir.display()).unwrap();
+
+    return 0;
+}
@@ -0,0 +1,17 @@
+use rustc_serialize::json::ToJson;
+use std::io::Write;
+
+use facts::Facts;
+
+// Print the facts `tsh run` would give packages, as the env vars they'd see.
+pub fn main<W: Write>(out: &mut W, json: bool) -> i32 {
+    let facts = Facts::gather();
+    if json {
+        writeln!(out, "{}", facts.to_json().pretty()).unwrap();
+    } else {
+        for (k, v) in facts.to_env() {
+            writeln!(out, "{}={}", k, v).unwrap();
+        }
+    }
+    0
+}
@@ -0,0 +1,137 @@
+use sodiumoxide::crypto::sign;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{Json, ToJson};
+
+use tempdir;
+
+use lockfile;
+use lockfile::Lockfile;
+use resolve::{Edge, Graph};
+use runlist;
+use unpack;
+
+// A DOT string, which can have anything in it but `"` and `\` escaped.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+fn write_edge<W: Write>(out: &mut W, from: &str, edge: &Edge) {
+    match edge.condition {
+        Some(ref when) => writeln!(out, "  {} -> {} [label={}, style=dashed];", quote(from), quote(&edge.name), quote(&when.to_string())).unwrap(),
+        None => writeln!(out, "  {} -> {};", quote(from), quote(&edge.name)).unwrap()
+    }
+}
+
+// Roles are boxes, named "role <name>" so they can't clash with packages, and
+// each package's label starts with its place in the run order.
+fn write_dot<W: Write>(out: &mut W, graph: &Graph) {
+    writeln!(out, "digraph turboshell {{").unwrap();
+    for &(ref role, ref edges) in graph.roles.iter() {
+        let id = format!("role {}", role);
+        writeln!(out, "  {} [shape=box];", quote(&id)).unwrap();
+        for edge in edges {
+            write_edge(out, &id, edge);
+        }
+    }
+    for (i, node) in graph.packages.iter().enumerate() {
+        writeln!(out, "  {} [label={}];", quote(&node.name), quote(&format!("{}. {} {}", i + 1, node.name, node.version))).unwrap();
+        for edge in node.dependencies.iter() {
+            write_edge(out, &node.name, edge);
+        }
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn edges_to_json(edges: &[Edge]) -> Json {
+    Json::Array(edges.iter().map(|edge| {
+        let mut m = BTreeMap::new();
+        m.insert("name".to_string(), edge.name.to_json());
+        if let Some(ref when) = edge.condition {
+            m.insert("when".to_string(), when.to_string().to_json());
+        }
+        Json::Object(m)
+    }).collect())
+}
+
+fn graph_to_json(graph: &Graph) -> Json {
+    let roles = graph.roles.iter().map(|&(ref role, ref edges)| {
+        let mut m = BTreeMap::new();
+        m.insert("name".to_string(), role.to_json());
+        m.insert("dependencies".to_string(), edges_to_json(edges));
+        Json::Object(m)
+    }).collect();
+
+    let packages = graph.packages.iter().enumerate().map(|(i, node)| {
+        let mut m = BTreeMap::new();
+        m.insert("name".to_string(), node.name.to_json());
+        m.insert("version".to_string(), node.version.to_json());
+        m.insert("order".to_string(), (i + 1).to_json());
+        m.insert("dependencies".to_string(), edges_to_json(&node.dependencies));
+        Json::Object(m)
+    }).collect();
+
+    let mut m = BTreeMap::new();
+    m.insert("roles".to_string(), Json::Array(roles));
+    m.insert("packages".to_string(), Json::Array(packages));
+    Json::Object(m)
+}
+
+// Resolved the way compile would: for every host, sticking to turboshell.lock.
+pub fn graph_of_roles(directory_string: &str, roles: &Vec<String>) -> Result<Graph, String> {
+    let basedir = try!(PathBuf::from(directory_string).canonicalize().map_err(|e| format!("Can't find directory {}: {}", directory_string, e)));
+    let mut runlist = try!(runlist::RunList::from_roles(&basedir, roles).map_err(|e| e.to_string()));
+    if let Some(lockfile) = try!(Lockfile::from_path(basedir.join(lockfile::FILENAME)).map_err(|e| e.to_string())) {
+        runlist.prefer_versions(lockfile.versions());
+    }
+    runlist.graph().map_err(|e| e.to_string())
+}
+
+fn graph_of_archive(pubkey_string: &str, archive_string: &str) -> Result<Graph, String> {
+    let mut input: Box<Read> = if archive_string != "" {
+        Box::new(try!(File::open(archive_string).map_err(|e| format!("Can't open {}: {}", archive_string, e))))
+    } else {
+        Box::new(io::stdin())
+    };
+
+    // Create PublicKey
+    let pubkey_bytes = try!(pubkey_string.as_bytes().from_base64().map_err(|_| format!("error decoding pubkey \"{}\" as base64", pubkey_string)));
+    let pubkey = try!(sign::PublicKey::from_slice(&pubkey_bytes).ok_or("error creating PublicKey".to_string()));
+
+    // Verify and Unpack
+    let tarball_bytes = try!(unpack::unpack(&mut input, pubkey).map_err(|e| e.to_string()));
+    let tempdir = try!(tempdir::TempDir::new("turboshell").map_err(|e| format!("error creating temp dir: {}", e)));
+    let basedir = try!(tempdir.path().canonicalize().map_err(|e| format!("Can't canonicalize temp dir: {}", e)));
+    try!(unpack::explode(tarball_bytes.as_slice(), &basedir).map_err(|e| format!("error unpacking archive: {}", e)));
+
+    let runlist = try!(runlist::RunList::from_archive(&basedir).map_err(|e| format!("error reading archive: {}", e)));
+    runlist.graph().map_err(|e| e.to_string())
+}
+
+pub fn main<W: Write>(out: &mut W, json: bool, pubkey_string: String, archive_string: String, directory_string: String, roles: Vec<String>) -> i32 {
+    let mut stderr = io::stderr();
+
+    let graph = if pubkey_string != "" {
+        graph_of_archive(&pubkey_string, &archive_string)
+    } else {
+        graph_of_roles(&directory_string, &roles)
+    };
+    let graph = match graph {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+
+    if json {
+        writeln!(out, "{}", graph_to_json(&graph).pretty()).unwrap();
+    } else {
+        write_dot(out, &graph);
+    }
+    return 0;
+}
@@ -4,9 +4,36 @@ use std::io;
 use std::io::{Read, Write};
 use rustc_serialize::base64::FromBase64;
 
+use tempdir;
+
+use facts::Facts;
+use runlist;
 use unpack;
 
-pub fn main<W: Write>(out: &mut W, output_string: String, pubkey_string: String, archive_string: String) -> i32 {
+// What `tsh run` would do on this host: which `when` conditions hold, and the
+// packages that would run, in order.
+fn write_plan<W: Write>(out: &mut W, tarball_bytes: &[u8]) -> Result<(), String> {
+    let tempdir = try!(tempdir::TempDir::new("turboshell").map_err(|e| format!("error creating temp dir: {}", e)));
+    let basedir = try!(tempdir.path().canonicalize().map_err(|e| format!("Can't canonicalize temp dir: {}", e)));
+    try!(unpack::explode(tarball_bytes, &basedir).map_err(|e| format!("error unpacking archive: {}", e)));
+
+    let mut runlist = try!(runlist::RunList::from_archive(&basedir).map_err(|e| format!("error reading archive: {}", e)));
+    runlist.use_facts(Facts::gather());
+
+    let conditions = try!(runlist.conditions().map_err(|e| e.to_string()));
+    let exes = try!(runlist.sort_dependencies().map_err(|e| e.to_string()));
+
+    if ! conditions.is_empty() {
+        writeln!(out, "Conditions:").unwrap();
+        for c in conditions {
+            writeln!(out, "  {} depends on {} when {}: {}", c.from, c.name, c.condition, c.holds).unwrap();
+        }
+    }
+    writeln!(out, "Plan: {}", exes.iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>().join(", ")).unwrap();
+    Ok(())
+}
+
+pub fn main<W: Write>(out: &mut W, plan: bool, output_string: String, pubkey_string: String, archive_string: String) -> i32 {
     let mut stderr = io::stderr();
 
     let mut input: Box<Read> = if archive_string != "" {
@@ -40,6 +67,14 @@ pub fn main<W: Write>(out: &mut W, output_string: String, pubkey_string: String,
         }
     };
 
+    if plan {
+        if let Err(e) = write_plan(out, &tarball_bytes) {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+        return 0;
+    }
+
     // Output the tarball
     let mut output: Box<Write> = if output_string != "" {
         Box::new(File::create(output_string).unwrap())
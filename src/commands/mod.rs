@@ -1,9 +1,24 @@
 mod keytool;
 mod compile;
+mod diff;
+mod extract;
+mod facts;
+mod graph;
 mod inspect;
 mod run;
+mod verify;
+mod why;
 
 pub use self::keytool::main as keytool;
 pub use self::compile::main as compile;
+pub use self::compile::Format as CompileFormat;
+pub use self::compile::Options as CompileOptions;
+pub use self::diff::main as diff;
+pub use self::extract::main as extract;
+pub use self::facts::main as facts;
+pub use self::graph::main as graph;
 pub use self::inspect::main as inspect;
 pub use self::run::main as run;
+pub use self::run::bundled as run_bundled;
+pub use self::verify::main as verify;
+pub use self::why::main as why;
@@ -1,7 +1,10 @@
 use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::ToJson;
+use std::fmt;
+use std::fs;
 use std::fs::File;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::io;
 use std::io::{BufRead, Read, Write};
 use std::iter;
@@ -9,21 +12,89 @@ use sodiumoxide::crypto::sign;
 
 use tempdir;
 
+use bundle;
+use facts::Facts;
 use unpack;
 use resolve;
 use runlist;
+use template;
+
+// What running a package did, for the summary at the end of a run.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+    Changed,
+    Unchanged,
+    Failed,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Status::Changed => write!(f, "changed"),
+            Status::Unchanged => write!(f, "unchanged"),
+            Status::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+fn write_summary<W: Write>(out: &mut W, statuses: &[(String, Status)]) {
+    let count = |status| statuses.iter().filter(|&&(_, s)| s == status).count();
+
+    writeln!(out, "\nSummary:").unwrap();
+    for &(ref name, status) in statuses {
+        writeln!(out, "  {}: {}", name, status).unwrap();
+    }
+    writeln!(out, "{} changed, {} unchanged, {} failed",
+             count(Status::Changed), count(Status::Unchanged), count(Status::Failed)).unwrap();
+}
 
 impl runlist::RunList {
-    pub fn run<W: Write>(&self, out: &mut W) -> io::Result<()> {
+    // Run every package's main, or the named action of every package that
+    // has it. Uninstalling goes in reverse so dependents go first. Every
+    // package gets the host's facts in its env.
+    pub fn run<W: Write>(&self, out: &mut W, action: Option<&str>) -> io::Result<()> {
         match self.sort_dependencies() {
-            Ok(deps) => {
-                writeln!(out, "Running: {}", deps.iter().map(|p| p.name.clone()).collect::<Vec<String>>().join(", ")).unwrap();
+            Ok(mut deps) => {
+                if let Some(facts) = self.facts() {
+                    for package in deps.iter_mut() {
+                        package.env.extend(facts.to_env());
+                    }
+                }
+
+                let mut deps = match action {
+                    Some(action) => deps.into_iter().filter(|p| p.actions.contains_key(action)).collect(),
+                    None => deps
+                };
+                if action == Some("uninstall") {
+                    deps.reverse();
+                }
+
+                let names = deps.iter().map(|p| p.name.clone()).collect::<Vec<String>>().join(", ");
+                match action {
+                    Some(action) => writeln!(out, "Running {}: {}", action, names).unwrap(),
+                    None => writeln!(out, "Running: {}", names).unwrap()
+                }
 
+                // stop at the first failure, since later packages may depend on it
+                let mut statuses = Vec::with_capacity(deps.len());
+                let mut result = Ok(());
                 for package in deps {
-                    try!(package.run(out));
+                    let status = match action {
+                        Some(action) => package.run_action(out, action),
+                        None => package.run(out)
+                    };
+                    match status {
+                        Ok(status) => statuses.push((package.name, status)),
+                        Err(e) => {
+                            statuses.push((package.name, Status::Failed));
+                            result = Err(e);
+                            break;
+                        }
+                    }
                 }
 
-                Ok(())
+                write_summary(out, &statuses);
+                result
             },
             Err(e) => {
                 Err(io::Error::new(io::ErrorKind::InvalidData, e.message()))
@@ -32,7 +103,7 @@ impl runlist::RunList {
     }
 }
 
-fn run_command<P: AsRef<Path>, W: Write>(out: &mut W, main: &str, dir: P, env: &runlist::Env) -> io::Result<()>{
+fn run_command<P: AsRef<Path>, W: Write>(out: &mut W, main: &str, dir: P, env: &runlist::Env) -> io::Result<ExitStatus>{
     let mut command = Command::new(main);
     command.current_dir(dir);
     //command.env_clear();
@@ -54,27 +125,155 @@ fn run_command<P: AsRef<Path>, W: Write>(out: &mut W, main: &str, dir: P, env: &
         }
     }
 
-    let status = try!(child.wait());
+    child.wait()
+}
+
+fn write_header<W: Write>(out: &mut W, text: &str) {
+    let line = iter::repeat("#").take(text.len()).collect::<String>();
+    writeln!(out, "\n{}\n{}\n{}\n", line, text, line).unwrap();
+}
 
-    if ! status.success() {
-        Err(io::Error::new(io::ErrorKind::Other, format!("{} returned {}", main, status)))
-    } else {
+impl resolve::Executable {
+    // Render every template with the package's env, keeping the template's
+    // permissions on the output.
+    pub fn render_templates(&self) -> io::Result<()> {
+        for (template, output) in self.templates.iter() {
+            let rendered = match template::render_file(template, &self.env) {
+                Ok(v) => v,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            };
+            if let Some(parent) = output.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            try!(try!(File::create(output)).write_all(rendered.as_bytes()));
+            try!(fs::set_permissions(output, try!(fs::metadata(template)).permissions()));
+        }
         Ok(())
     }
+
+    pub fn run<W: Write>(&self, out: &mut W) -> io::Result<Status> {
+        write_header(out, &format!("##    Running Package {}    ##", self.name));
+        try!(self.render_templates());
+
+        // a passing check means there's nothing for main to do
+        if let Some(ref check) = self.check {
+            if try!(run_command(out, check.to_str().unwrap(), &self.dir, &self.env)).success() {
+                writeln!(out, "check passed, skipping main").unwrap();
+                return Ok(Status::Unchanged);
+            }
+        }
+
+        let main = self.main.to_str().unwrap();
+        let status = try!(run_command(out, main, &self.dir, &self.env));
+        if ! status.success() {
+            Err(io::Error::new(io::ErrorKind::Other, format!("{} returned {}", main, status)))
+        } else {
+            Ok(Status::Changed)
+        }
+    }
+
+    pub fn run_action<W: Write>(&self, out: &mut W, action: &str) -> io::Result<Status> {
+        let path = match self.actions.get(action) {
+            Some(path) => path.to_str().unwrap(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("package {} has no {} action", self.name, action)))
+        };
+
+        write_header(out, &format!("##    Running {} for Package {}    ##", action, self.name));
+        try!(self.render_templates());
+
+        let status = try!(run_command(out, path, &self.dir, &self.env));
+        if ! status.success() {
+            Err(io::Error::new(io::ErrorKind::Other, format!("{} returned {}", path, status)))
+        } else {
+            Ok(Status::Changed)
+        }
+    }
 }
 
-impl resolve::Executable {
-    pub fn run<W: Write>(&self, out: &mut W) -> io::Result<()> {
-        let text = format!("##    Running Package {}    ##", self.name);
-        let line = iter::repeat("#").take(text.len()).collect::<String>();
-        writeln!(out, "\n{}\n{}\n{}\n", line, text, line).unwrap();
-        run_command(out, self.main.to_str().unwrap(), &self.dir, &self.env)
+fn run_basedir<W: Write, P: AsRef<Path>>(out: &mut W, basedir: &P, action: Option<&str>) -> i32 {
+    let mut stderr = io::stderr();
+
+    let mut runlist = match runlist::RunList::from_archive(basedir) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "error reading archive: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    if runlist.is_expired() {
+        writeln!(&mut stderr, "error running archive: archive has expired").unwrap();
+        return 1;
+    }
+
+    // gathered once for the whole run, and left in the run dir for anyone
+    // debugging it
+    let facts = Facts::gather();
+    if let Err(e) = File::create(basedir.as_ref().join("facts.json")).and_then(|mut f| writeln!(f, "{}", facts.to_json().pretty())) {
+        writeln!(&mut stderr, "error writing facts: {}", e).unwrap();
+        return 1;
     }
+    runlist.use_facts(facts);
+
+    if let Err(e) = runlist.run(out, action) {
+        writeln!(&mut stderr, "error running archive: {}", e).unwrap();
+        return 1;
+    }
+
+    return 0;
 }
 
-pub fn main<W: Write>(out: &mut W, pubkey_string: String, archive_string: String) -> i32 {
+fn run_archive<R: Read, W: Write>(out: &mut W, input: &mut R, pubkey: sign::PublicKey, action: Option<&str>) -> i32 {
     let mut stderr = io::stderr();
 
+    // Create a place for the archive to be unpacked
+    let tempdir = match tempdir::TempDir::new("turboshell") {
+        Ok(value) => value,
+        Err(e) => {
+            writeln!(&mut stderr, "error creating temp dir: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    let basedir = match tempdir.path().canonicalize() {
+        Ok(value) => value,
+        Err(e) => {
+            writeln!(&mut stderr, "Can't canonicalize temp dir: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    let tarball_bytes = match unpack::unpack(input, pubkey) {
+        Ok(value) => value,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+    if let Err(e) = unpack::explode(tarball_bytes.as_slice(), &basedir) {
+        writeln!(&mut stderr, "error unpacking archive: {}", e).unwrap();
+        return 1;
+    }
+
+    run_basedir(out, &basedir, action)
+}
+
+pub fn main<W: Write>(out: &mut W, action_string: String, pubkey_string: String, archive_string: String, directory_string: String) -> i32 {
+    let mut stderr = io::stderr();
+    let action = if action_string != "" { Some(action_string.as_str()) } else { None };
+
+    // An already extracted archive (see `tsh extract`) is run in place
+    if directory_string != "" {
+        let basedir = match PathBuf::from(&directory_string).canonicalize() {
+            Ok(value) => value,
+            Err(e) => {
+                writeln!(&mut stderr, "Can't find directory {}: {}", directory_string, e).unwrap();
+                return 1;
+            }
+        };
+        return run_basedir(out, &basedir, action);
+    }
+
     let mut input: Box<Read> = if archive_string != "" {
         Box::new(File::open(archive_string).unwrap())
     } else {
@@ -97,38 +296,90 @@ pub fn main<W: Write>(out: &mut W, pubkey_string: String, archive_string: String
         }
     };
 
-    // Create a place for the archive to be unpacked
-    let tempdir = match tempdir::TempDir::new("turboshell") {
-        Ok(value) => value,
-        Err(e) => {
-            writeln!(&mut stderr, "error creating temp dir: {}", e).unwrap();
+    run_archive(out, &mut input, pubkey, action)
+}
+
+// The key that self-contained binaries verify against when there's no `-k`,
+// set with TSH_TRUSTED_KEY when building tsh.
+const TRUSTED_KEY: Option<&'static str> = option_env!("TSH_TRUSTED_KEY");
+
+// Run the archive embedded in a self-contained binary. It's verified against
+// the key given on the command line, or the one built into tsh.
+pub fn bundled<W: Write>(out: &mut W, action_string: String, pubkey_string: String, bundle: bundle::Bundle) -> i32 {
+    let mut stderr = io::stderr();
+    let action = if action_string != "" { Some(action_string.as_str()) } else { None };
+
+    let pubkey_string = match (pubkey_string.as_str(), TRUSTED_KEY) {
+        ("", Some(key)) => key.to_string(),
+        ("", None) => {
+            writeln!(&mut stderr, "This tsh has no trusted key built in, so pass the signer's public key with -k KEY").unwrap();
             return 1;
-        }
+        },
+        _ => pubkey_string
     };
 
-    let basedir = match tempdir.path().canonicalize() {
-        Ok(value) => value,
-        Err(e) => {
-            writeln!(&mut stderr, "Can't canonicalize temp dir: {}", e).unwrap();
+    // Create PublicKey
+    let pubkey_bytes = match pubkey_string.as_bytes().from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            writeln!(&mut stderr, "error decoding pubkey \"{}\" as base64", pubkey_string).unwrap();
             return 1;
         }
     };
-
-    let tarball_bytes = unpack::unpack(&mut input, pubkey).unwrap();
-    unpack::explode(tarball_bytes.as_slice(), &basedir);
-
-    let runlist = match runlist::RunList::from_archive(&basedir) {
-        Ok(v) => v,
-        Err(e) => {
-            writeln!(&mut stderr, "error reading archive: {}", e).unwrap();
+    let pubkey = match sign::PublicKey::from_slice(&pubkey_bytes) {
+        Some(key) => key,
+        None => {
+            writeln!(&mut stderr, "error creating PublicKey").unwrap();
             return 1;
         }
     };
 
-    if let Err(e) = runlist.run(out) {
-        writeln!(&mut stderr, "error running archive: {}", e).unwrap();
-        return 1;
+    run_archive(out, &mut bundle.archive.as_slice(), pubkey, action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Status;
+    use resolve::Executable;
+    use runlist::{Env, Package};
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+
+    fn resource<P: AsRef<Path>>(path: P) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("resources").join(path)
     }
 
-    return 0;
+    fn executable(package: &Package, env: Env) -> Executable {
+        Executable{dir: package.dir.clone(),
+                   name: package.name.clone(),
+                   version: package.version().to_string(),
+                   main: package.main.clone(),
+                   check: package.check.clone(),
+                   actions: BTreeMap::new(),
+                   templates: BTreeMap::new(),
+                   env: env,
+                   dependencies: vec![]}
+    }
+
+    #[test]
+    fn passing_check_skips_main() {
+        let package = Package::from_file(&resource("package_unit_tests"), "check_passes").unwrap();
+        let tempdir = TempDir::new("run").unwrap();
+        let marker = tempdir.path().join("main_ran");
+
+        let mut env = Env::new();
+        env.insert("MARKER".to_string(), marker.to_str().unwrap().to_string());
+        env.insert("ALREADY_DONE".to_string(), "yes".to_string());
+        let mut out = io::Cursor::new(Vec::new());
+        assert_eq!(executable(&package, env.clone()).run(&mut out).unwrap(), Status::Unchanged);
+        assert!(String::from_utf8(out.into_inner()).unwrap().contains("check passed, skipping main"));
+        assert!(!marker.exists());
+
+        // and a failing one doesn't
+        env.remove("ALREADY_DONE");
+        assert_eq!(executable(&package, env).run(&mut io::Cursor::new(Vec::new())).unwrap(), Status::Changed);
+        assert!(marker.exists());
+    }
 }
@@ -0,0 +1,177 @@
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{Json, ToJson};
+use sodiumoxide::crypto::sign;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+use metadata;
+use metadata::Metadata;
+use unpack;
+use unpack::VerifyError;
+
+// Exit codes are part of the interface: deploy gates key off of them.
+const OK: i32 = 0;
+const ERROR: i32 = 1;
+const BAD_HEADER: i32 = 2;
+const BAD_SIGNATURE_ENCODING: i32 = 3;
+const SIGNATURE_MISMATCH: i32 = 4;
+const EXPIRED: i32 = 5;
+const MALFORMED_PAYLOAD: i32 = 6;
+
+fn status(exit_code: i32) -> &'static str {
+    match exit_code {
+        OK => "ok",
+        BAD_HEADER => "bad_header",
+        BAD_SIGNATURE_ENCODING => "bad_signature_encoding",
+        SIGNATURE_MISMATCH => "signature_mismatch",
+        EXPIRED => "expired",
+        MALFORMED_PAYLOAD => "malformed_payload",
+        _ => "error",
+    }
+}
+
+struct Report {
+    exit_code: i32,
+    error: Option<String>,
+    signer: Option<String>,
+    metadata: Option<Metadata>,
+}
+
+impl Report {
+    fn failure(exit_code: i32, error: String) -> Report {
+        Report{exit_code: exit_code, error: Some(error), signer: None, metadata: None}
+    }
+}
+
+impl ToJson for Report {
+    fn to_json(&self) -> Json {
+        let mut m = BTreeMap::new();
+        m.insert("status".to_string(), status(self.exit_code).to_json());
+        m.insert("exit_code".to_string(), self.exit_code.to_json());
+        m.insert("error".to_string(), self.error.to_json());
+        m.insert("signer".to_string(), self.signer.to_json());
+        m.insert("archive".to_string(), match self.metadata {
+            Some(ref metadata) => metadata.to_json(),
+            None => Json::Null
+        });
+        Json::Object(m)
+    }
+}
+
+fn check<R: Read>(input: &mut R, pubkey_string: &str) -> Report {
+    // Create PublicKey
+    let pubkey_bytes = match pubkey_string.as_bytes().from_base64() {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Report::failure(ERROR, format!("error decoding pubkey \"{}\" as base64", pubkey_string));
+        }
+    };
+    let pubkey = match sign::PublicKey::from_slice(&pubkey_bytes) {
+        Some(key) => key,
+        None => {
+            return Report::failure(ERROR, "error creating PublicKey".to_string());
+        }
+    };
+
+    // Verify
+    let tarball_bytes = match unpack::verify(input, &pubkey) {
+        Ok(value) => value,
+        Err(e) => {
+            let exit_code = match e {
+                VerifyError::Io(_) => ERROR,
+                VerifyError::InvalidHeader => BAD_HEADER,
+                VerifyError::InvalidSignatureEncoding(_) => BAD_SIGNATURE_ENCODING,
+                VerifyError::SignatureMismatch => SIGNATURE_MISMATCH,
+            };
+            return Report::failure(exit_code, e.to_string());
+        }
+    };
+
+    let mut report = Report{exit_code: OK, error: None, signer: Some(pubkey_string.to_string()), metadata: None};
+
+    // Check that the payload is a tarball with a usable archive.toml
+    let archive_toml = match unpack::read_file(tarball_bytes.as_slice(), "archive.toml") {
+        Ok(Some(bytes)) => match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(_) => {
+                report.exit_code = MALFORMED_PAYLOAD;
+                report.error = Some("archive.toml: failure to read - perhaps invalid UTF-8?".to_string());
+                return report;
+            }
+        },
+        Ok(None) => {
+            report.exit_code = MALFORMED_PAYLOAD;
+            report.error = Some("archive.toml is missing".to_string());
+            return report;
+        },
+        Err(e) => {
+            report.exit_code = MALFORMED_PAYLOAD;
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+
+    let metadata = match Metadata::from_str("archive.toml", &archive_toml) {
+        Ok(v) => v,
+        Err(e) => {
+            report.exit_code = MALFORMED_PAYLOAD;
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+
+    if metadata.is_expired() {
+        report.exit_code = EXPIRED;
+        report.error = Some(format!("archive expired at {}", metadata::format_timestamp(metadata.expires.unwrap())));
+    }
+    report.metadata = Some(metadata);
+
+    report
+}
+
+fn write_text<W: Write>(out: &mut W, report: &Report) {
+    if let Some(ref metadata) = report.metadata {
+        writeln!(out, "signer:  {}", report.signer.as_ref().unwrap()).unwrap();
+        writeln!(out, "roles:   {}", metadata.roles.join(", ")).unwrap();
+        if let Some(created) = metadata.created {
+            writeln!(out, "created: {}", metadata::format_timestamp(created)).unwrap();
+        }
+        if let Some(expires) = metadata.expires {
+            writeln!(out, "expires: {}", metadata::format_timestamp(expires)).unwrap();
+        }
+    }
+    if report.exit_code == OK {
+        writeln!(out, "OK").unwrap();
+    }
+}
+
+pub fn main<W: Write>(out: &mut W, json: bool, pubkey_string: String, archive_string: String) -> i32 {
+    let mut stderr = io::stderr();
+    let pubkey_string = pubkey_string.trim();
+
+    let report = {
+        let input: io::Result<Box<Read>> = if archive_string != "" {
+            File::open(&archive_string).map(|f| Box::new(f) as Box<Read>)
+        } else {
+            Ok(Box::new(io::stdin()))
+        };
+
+        match input {
+            Ok(mut input) => check(&mut input, pubkey_string),
+            Err(e) => Report::failure(ERROR, format!("{}: {}", e, archive_string))
+        }
+    };
+
+    if json {
+        writeln!(out, "{}", report.to_json().pretty()).unwrap();
+    } else {
+        write_text(out, &report);
+        if let Some(ref error) = report.error {
+            writeln!(&mut stderr, "{}", error).unwrap();
+        }
+    }
+
+    report.exit_code
+}
@@ -0,0 +1,44 @@
+use std::io;
+use std::io::Write;
+
+use super::graph::graph_of_roles;
+
+// Past this many paths, more of them don't help.
+const MAX_PATHS: usize = 20;
+
+// One line per path, like `role web -> app -> cache (when memory >= 2G)`.
+pub fn main<W: Write>(out: &mut W, directory_string: String, roles_string: String, package: String) -> i32 {
+    let mut stderr = io::stderr();
+
+    let roles = roles_string.split(',').map(|role| role.trim().to_string()).filter(|role| role != "").collect::<Vec<String>>();
+    let graph = match graph_of_roles(&directory_string, &roles) {
+        Ok(v) => v,
+        Err(e) => {
+            writeln!(&mut stderr, "{}", e).unwrap();
+            return 1;
+        }
+    };
+
+    let mut paths = graph.paths(&package, MAX_PATHS + 1);
+    if paths.is_empty() {
+        writeln!(&mut stderr, "{} isn't used by {}", package, roles.join(", ")).unwrap();
+        return 1;
+    }
+
+    let more = paths.len() > MAX_PATHS;
+    paths.truncate(MAX_PATHS);
+    for (role, path) in paths {
+        let mut line = format!("role {}", role);
+        for edge in path {
+            line.push_str(&format!(" -> {}", edge.name));
+            if let Some(ref when) = edge.condition {
+                line.push_str(&format!(" (when {})", when));
+            }
+        }
+        writeln!(out, "{}", line).unwrap();
+    }
+    if more {
+        writeln!(out, "(and more, only the first {} are shown)", MAX_PATHS).unwrap();
+    }
+    return 0;
+}
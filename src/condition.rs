@@ -0,0 +1,378 @@
+use std::fmt;
+use std::result;
+
+use facts::Facts;
+
+// A `when` expression on a dependency, over the host's facts, like
+//
+//     os_family == "debian" && (memory >= 2G || hostname == "db-*")
+//
+// String facts are matched against globs, where `*` is any run of characters
+// and `?` is any one character. cpus and memory are numbers, and memory can be
+// written with a K, M, G or T suffix.
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Glob(String, Op, String),
+    Number(String, Op, u64),
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Token {
+    Name(String),
+    Str(String),
+    Number(u64),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Name(ref s) => write!(f, "`{}`", s),
+            Token::Str(ref s) => write!(f, "\"{}\"", s),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Symbol(s) => write!(f, "`{}`", s),
+        }
+    }
+}
+
+const STRING_FACTS: &'static [&'static str] = &["os", "os_family", "os_version", "os_name", "kernel", "arch", "hostname"];
+const NUMBER_FACTS: &'static [&'static str] = &["cpus", "memory"];
+const SYMBOLS: &'static [&'static str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")"];
+
+fn skip_whitespace(s: &str) -> &str {
+    s.find(|c: char| !c.is_whitespace()).map(|i| &s[i..]).unwrap_or("")
+}
+
+fn tokenize(s: &str) -> result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = skip_whitespace(s);
+
+    while let Some(c) = rest.chars().next() {
+        if c == '"' || c == '\'' {
+            match rest[1..].find(c) {
+                Some(end) => {
+                    tokens.push(Token::Str(rest[1..end + 1].to_string()));
+                    rest = &rest[end + 2..];
+                },
+                None => return Err(format!("{} isn't closed", rest))
+            }
+        } else if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            match number(&rest[..end]) {
+                Some(n) => tokens.push(Token::Number(n)),
+                None => return Err(format!("\"{}\" isn't a number", &rest[..end]))
+            }
+            rest = &rest[end..];
+        } else if c == '_' || c.is_ascii_alphabetic() {
+            let end = rest.find(|c: char| c != '_' && !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                Some(symbol) => {
+                    tokens.push(Token::Symbol(symbol));
+                    rest = &rest[symbol.len()..];
+                },
+                None => return Err(format!("`{}` isn't allowed", c))
+            }
+        }
+        rest = skip_whitespace(rest);
+    }
+
+    Ok(tokens)
+}
+
+// 512, 512M, 2G and so on, in bytes.
+fn number(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1)
+    };
+    digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    i: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.i)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.i).cloned();
+        self.i += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(&Token::Symbol(s)) if s == symbol => {},
+            _ => return false
+        }
+        self.i += 1;
+        true
+    }
+
+    fn or(&mut self) -> result::Result<Expr, String> {
+        let mut expr = try!(self.and());
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(try!(self.and())));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> result::Result<Expr, String> {
+        let mut expr = try!(self.not());
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(try!(self.not())));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> result::Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(try!(self.not()))));
+        }
+        if self.eat("(") {
+            let expr = try!(self.or());
+            if ! self.eat(")") {
+                return Err(match self.peek() {
+                    Some(token) => format!("expected `)` but found {}", token),
+                    None => "expected `)`".to_string()
+                });
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> result::Result<Expr, String> {
+        let fact = match self.next() {
+            Some(Token::Name(name)) => name,
+            Some(token) => return Err(format!("expected a fact but found {}", token)),
+            None => return Err("expected a fact".to_string())
+        };
+        let op = match self.next() {
+            Some(Token::Symbol("==")) => Op::Eq,
+            Some(Token::Symbol("!=")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            Some(token) => return Err(format!("expected a comparison after `{}` but found {}", fact, token)),
+            None => return Err(format!("expected a comparison after `{}`", fact))
+        };
+
+        if STRING_FACTS.contains(&fact.as_str()) {
+            if op != Op::Eq && op != Op::Ne {
+                return Err(format!("`{}` can only be compared with == and !=", fact));
+            }
+            match self.next() {
+                Some(Token::Str(pattern)) => Ok(Expr::Glob(fact, op, pattern)),
+                _ => Err(format!("`{}` has to be compared with a string, like \"web-*\"", fact))
+            }
+        } else if NUMBER_FACTS.contains(&fact.as_str()) {
+            match self.next() {
+                Some(Token::Number(n)) => Ok(Expr::Number(fact, op, n)),
+                _ => Err(format!("`{}` has to be compared with a number, like 2G", fact))
+            }
+        } else {
+            let facts = STRING_FACTS.iter().chain(NUMBER_FACTS).cloned().collect::<Vec<&str>>();
+            Err(format!("there's no fact called `{}`; facts are {}", fact, facts.join(", ")))
+        }
+    }
+}
+
+// `*` matches any run of characters and `?` any one.
+fn glob(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let s = s.chars().collect::<Vec<char>>();
+    let (mut p, mut i) = (0, 0);
+    // where the last `*` was, and how much of `s` it has taken so far
+    let mut star = None;
+
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = star {
+            p = star_p + 1;
+            i = star_i + 1;
+            star = Some((star_p, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn string_fact<'a>(facts: &'a Facts, name: &str) -> &'a str {
+    match name {
+        "os" => &facts.os,
+        "os_family" => &facts.os_family,
+        "os_version" => &facts.os_version,
+        "os_name" => &facts.os_name,
+        "kernel" => &facts.kernel,
+        "arch" => &facts.arch,
+        "hostname" => &facts.hostname,
+        _ => unreachable!()
+    }
+}
+
+fn number_fact(facts: &Facts, name: &str) -> u64 {
+    match name {
+        "cpus" => facts.cpus,
+        "memory" => facts.memory,
+        _ => unreachable!()
+    }
+}
+
+impl Expr {
+    fn eval(&self, facts: &Facts) -> bool {
+        match *self {
+            Expr::And(ref a, ref b) => a.eval(facts) && b.eval(facts),
+            Expr::Or(ref a, ref b) => a.eval(facts) || b.eval(facts),
+            Expr::Not(ref a) => ! a.eval(facts),
+            Expr::Glob(ref fact, op, ref pattern) => glob(pattern, string_fact(facts, fact)) == (op == Op::Eq),
+            Expr::Number(ref fact, op, n) => {
+                let value = number_fact(facts, fact);
+                match op {
+                    Op::Eq => value == n,
+                    Op::Ne => value != n,
+                    Op::Lt => value < n,
+                    Op::Le => value <= n,
+                    Op::Gt => value > n,
+                    Op::Ge => value >= n,
+                }
+            }
+        }
+    }
+}
+
+impl Condition {
+    // Err says what's wrong with it, e.g. "there's no fact called `ram`".
+    pub fn parse(s: &str) -> result::Result<Condition, String> {
+        let mut parser = Parser{tokens: try!(tokenize(s)), i: 0};
+        let expr = try!(parser.or());
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {}", token));
+        }
+        Ok(Condition{source: s.to_string(), expr: expr})
+    }
+
+    pub fn holds(&self, facts: &Facts) -> bool {
+        self.expr.eval(facts)
+    }
+}
+
+// Whether something with this condition is needed. Without facts, as when
+// compiling, every condition counts as true so that the archive has whatever
+// any host could need.
+pub fn applies(condition: Option<&Condition>, facts: Option<&Facts>) -> bool {
+    match (condition, facts) {
+        (Some(condition), Some(facts)) => condition.holds(facts),
+        _ => true
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, number, Condition};
+    use facts::Facts;
+
+    fn facts() -> Facts {
+        Facts{os: "ubuntu".to_string(),
+              os_family: "debian".to_string(),
+              arch: "x86_64".to_string(),
+              hostname: "web-3.example.com".to_string(),
+              cpus: 4,
+              memory: 4 << 30,
+              ..Facts::default()}
+    }
+
+    fn holds(s: &str) -> bool {
+        Condition::parse(s).unwrap().holds(&facts())
+    }
+
+    #[test]
+    fn evaluates() {
+        assert!(holds("os_family == \"debian\""));
+        assert!(!holds("os_family == 'alpine'"));
+        assert!(holds("os != \"debian\""));
+        assert!(holds("hostname == \"web-*\""));
+        assert!(!holds("hostname == \"db-?.*\""));
+        assert!(holds("memory >= 2G && cpus > 2"));
+        assert!(!holds("memory < 4096M"));
+        assert!(holds("memory == 4G"));
+        assert!(holds("arch == \"arm*\" || !(cpus < 4)"));
+        assert!(!holds("!os_family == \"debian\""));
+        assert!(holds("os == \"alpine\" || os == \"ubuntu\" && arch == \"x86_64\""));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = |s: &str| Condition::parse(s).err().unwrap();
+        assert_eq!(err("ram > 2G"), "there's no fact called `ram`; facts are os, os_family, os_version, os_name, kernel, arch, hostname, cpus, memory");
+        assert_eq!(err("hostname > \"a\""), "`hostname` can only be compared with == and !=");
+        assert_eq!(err("os == debian"), "`os` has to be compared with a string, like \"web-*\"");
+        assert_eq!(err("memory > \"2G\""), "`memory` has to be compared with a number, like 2G");
+        assert_eq!(err("memory > 2X"), "\"2X\" isn't a number");
+        assert_eq!(err("os == \"debian"), "\"debian isn't closed");
+        assert_eq!(err("(os == \"a\""), "expected `)`");
+        assert_eq!(err("os == \"a\" os"), "unexpected `os`");
+        assert_eq!(err("os = \"a\""), "`=` isn't allowed");
+        assert_eq!(err(""), "expected a fact");
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*", ""));
+        assert!(glob("web-*", "web-"));
+        assert!(glob("*.example.com", "a.b.example.com"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(!glob("web", "web-1"));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(number("512"), Some(512));
+        assert_eq!(number("2G"), Some(2 << 30));
+        assert_eq!(number("1k"), Some(1024));
+        assert_eq!(number("99999999999T"), None);
+    }
+}
@@ -0,0 +1,214 @@
+use libc;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ptr;
+
+use runlist::Env;
+
+// What tsh knows about the host it's running on. Anything that can't be
+// found out is left empty, or 0, rather than failing the run.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Facts {
+    pub os: String,
+    pub os_family: String,
+    pub os_version: String,
+    pub os_name: String,
+    pub kernel: String,
+    pub arch: String,
+    pub hostname: String,
+    pub cpus: u64,
+    pub memory: u64,
+    pub ip_addresses: Vec<String>,
+}
+
+fn read_file(path: &str) -> Option<String> {
+    let mut s = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) => Some(s),
+        Err(_) => None
+    }
+}
+
+// os-release is shell-style `KEY=value` lines, where the value may be quoted.
+fn parse_os_release(s: &str) -> BTreeMap<String, String> {
+    let mut release = BTreeMap::new();
+    for line in s.lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(i) = line.find('=') {
+            let value = line[i + 1..].trim();
+            let value = if value.len() >= 2 && (value.starts_with('"') && value.ends_with('"') || value.starts_with('\'') && value.ends_with('\'')) {
+                value[1..value.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\")
+            } else {
+                value.to_string()
+            };
+            release.insert(line[..i].trim().to_string(), value);
+        }
+    }
+    release
+}
+
+// /proc/meminfo has `MemTotal:       16316412 kB`.
+fn parse_meminfo(s: &str) -> Option<u64> {
+    s.lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+fn c_string(s: &[libc::c_char]) -> String {
+    unsafe { CStr::from_ptr(s.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+fn ip_addresses() -> Vec<String> {
+    let mut addresses = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return addresses;
+    }
+
+    let mut ifa = ifaddrs;
+    while ! ifa.is_null() {
+        let (addr, flags, next) = unsafe { ((*ifa).ifa_addr, (*ifa).ifa_flags, (*ifa).ifa_next) };
+        if ! addr.is_null() && flags & libc::IFF_LOOPBACK as libc::c_uint == 0 {
+            match unsafe { (*addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(addr as *const libc::sockaddr_in) };
+                    addresses.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).to_string());
+                },
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(addr as *const libc::sockaddr_in6) };
+                    addresses.push(Ipv6Addr::from(sin6.sin6_addr.s6_addr).to_string());
+                },
+                _ => {}
+            }
+        }
+        ifa = next;
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+impl Facts {
+    pub fn gather() -> Facts {
+        let mut facts = Facts::default();
+
+        if let Some(s) = read_file("/etc/os-release").or_else(|| read_file("/usr/lib/os-release")) {
+            let mut release = parse_os_release(&s);
+            let os = release.remove("ID").unwrap_or_default();
+            // ubuntu is `ID_LIKE="debian"`, centos is `ID_LIKE="rhel fedora"`
+            facts.os_family = release.get("ID_LIKE")
+                .and_then(|like| like.split_whitespace().next())
+                .map(|family| family.to_string())
+                .unwrap_or_else(|| os.clone());
+            facts.os = os;
+            facts.os_version = release.remove("VERSION_ID").unwrap_or_default();
+            facts.os_name = release.remove("PRETTY_NAME").unwrap_or_default();
+        }
+
+        let mut uts: libc::utsname = unsafe { mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } == 0 {
+            facts.kernel = c_string(&uts.release);
+            facts.arch = c_string(&uts.machine);
+            facts.hostname = c_string(&uts.nodename);
+        }
+
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        if cpus > 0 {
+            facts.cpus = cpus as u64;
+        }
+
+        facts.memory = read_file("/proc/meminfo").and_then(|s| parse_meminfo(&s)).unwrap_or(0);
+        facts.ip_addresses = ip_addresses();
+        facts
+    }
+
+    // TSH_FACT_OS, TSH_FACT_HOSTNAME and so on. Lists are space separated,
+    // like array values in a package's env.
+    pub fn to_env(&self) -> Env {
+        let mut env = Env::new();
+        env.insert("TSH_FACT_OS".to_string(), self.os.clone());
+        env.insert("TSH_FACT_OS_FAMILY".to_string(), self.os_family.clone());
+        env.insert("TSH_FACT_OS_VERSION".to_string(), self.os_version.clone());
+        env.insert("TSH_FACT_OS_NAME".to_string(), self.os_name.clone());
+        env.insert("TSH_FACT_KERNEL".to_string(), self.kernel.clone());
+        env.insert("TSH_FACT_ARCH".to_string(), self.arch.clone());
+        env.insert("TSH_FACT_HOSTNAME".to_string(), self.hostname.clone());
+        env.insert("TSH_FACT_CPUS".to_string(), self.cpus.to_string());
+        env.insert("TSH_FACT_MEMORY".to_string(), self.memory.to_string());
+        env.insert("TSH_FACT_IP_ADDRESSES".to_string(), self.ip_addresses.join(" "));
+        env
+    }
+}
+
+impl ToJson for Facts {
+    fn to_json(&self) -> Json {
+        let mut m = BTreeMap::new();
+        m.insert("os".to_string(), self.os.to_json());
+        m.insert("os_family".to_string(), self.os_family.to_json());
+        m.insert("os_version".to_string(), self.os_version.to_json());
+        m.insert("os_name".to_string(), self.os_name.to_json());
+        m.insert("kernel".to_string(), self.kernel.to_json());
+        m.insert("arch".to_string(), self.arch.to_json());
+        m.insert("hostname".to_string(), self.hostname.to_json());
+        m.insert("cpus".to_string(), self.cpus.to_json());
+        m.insert("memory".to_string(), self.memory.to_json());
+        m.insert("ip_addresses".to_string(), self.ip_addresses.to_json());
+        Json::Object(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_meminfo, parse_os_release, Facts};
+
+    #[test]
+    fn os_release() {
+        let release = parse_os_release("# comment\nPRETTY_NAME=\"Debian GNU/Linux 8 (jessie)\"\nNAME='Debian GNU/Linux'\nVERSION_ID=\"8\"\nID=debian\n\n");
+        assert_eq!(release["PRETTY_NAME"], "Debian GNU/Linux 8 (jessie)");
+        assert_eq!(release["NAME"], "Debian GNU/Linux");
+        assert_eq!(release["VERSION_ID"], "8");
+        assert_eq!(release["ID"], "debian");
+        assert_eq!(release.len(), 4);
+    }
+
+    #[test]
+    fn meminfo() {
+        assert_eq!(parse_meminfo("MemTotal:        2048 kB\nMemFree:         1024 kB\n"), Some(2048 * 1024));
+        assert_eq!(parse_meminfo("MemFree:         1024 kB\n"), None);
+    }
+
+    #[test]
+    fn to_env() {
+        let facts = Facts{os: "debian".to_string(),
+                          hostname: "web1".to_string(),
+                          cpus: 4,
+                          ip_addresses: vec!["10.0.0.2".to_string(), "fe80::1".to_string()],
+                          ..Facts::default()};
+        let env = facts.to_env();
+        assert_eq!(env["TSH_FACT_OS"], "debian");
+        assert_eq!(env["TSH_FACT_HOSTNAME"], "web1");
+        assert_eq!(env["TSH_FACT_CPUS"], "4");
+        assert_eq!(env["TSH_FACT_MEMORY"], "0");
+        assert_eq!(env["TSH_FACT_IP_ADDRESSES"], "10.0.0.2 fe80::1");
+        assert_eq!(env["TSH_FACT_OS_NAME"], "");
+    }
+
+    #[test]
+    fn gather() {
+        let facts = Facts::gather();
+        assert!(facts.cpus >= 1);
+        assert!(facts.kernel != "");
+    }
+}
@@ -9,16 +9,28 @@ extern crate tar;
 extern crate toml;
 extern crate sodiumoxide;
 extern crate rustc_serialize;
+extern crate regex;
+extern crate semver;
 
 extern crate cast;
+extern crate chrono;
 extern crate walkdir;
 
 extern crate tempdir;
 
 
+pub mod bundle;
 pub mod commands;
+pub mod condition;
 pub mod error;
+pub mod facts;
+pub mod lockfile;
+pub mod metadata;
 pub mod resolve;
 pub mod runlist;
+pub mod schema;
 pub mod seedfile;
+pub mod template;
+pub mod textdiff;
 pub mod unpack;
+pub mod version;
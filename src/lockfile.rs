@@ -0,0 +1,249 @@
+use rustc_serialize::hex::ToHex;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use toml;
+use walkdir::{DirEntry, WalkDir, WalkDirIterator};
+
+use error::{Error, Result};
+use resolve::Executable;
+
+pub const FILENAME: &'static str = "turboshell.lock";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LockedPackage {
+    pub version: String,
+    pub hash: String,
+}
+
+// The version every package resolved to and a hash of what was in its
+// directory, so later compiles pick the same versions and notice changes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lockfile {
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name()
+        .to_str()
+        .map(|s| s.starts_with("."))
+        .unwrap_or(false)
+}
+
+// Like git, only the executable bit of a file's mode counts, so a lockfile
+// that's checked in matches checkouts made with any umask.
+fn mode(metadata: &fs::Metadata) -> &'static str {
+    if metadata.is_dir() {
+        "d"
+    } else if metadata.mode() & 0o111 != 0 {
+        "x"
+    } else {
+        "-"
+    }
+}
+
+// Hash the same files `tsh compile` puts in the archive: one line per entry
+// with its mode, path and (for files) the hash of its contents, then the
+// hash of all of that.
+pub fn hash_dir<P: AsRef<Path>>(dir: P) -> io::Result<String> {
+    let mut manifest = String::new();
+    for entry in WalkDir::new(dir.as_ref()).min_depth(1).sort_by(|a, b| a.cmp(b)).into_iter().filter_entry(|e| !is_hidden(e)) {
+        let entry = try!(entry);
+        let name = entry.path().strip_prefix(dir.as_ref()).unwrap().to_string_lossy().into_owned();
+        let metadata = try!(entry.metadata());
+
+        if metadata.is_dir() {
+            manifest.push_str(&format!("{} {}/\n", mode(&metadata), name));
+        } else {
+            let mut contents = Vec::new();
+            try!(try!(File::open(entry.path())).read_to_end(&mut contents));
+            manifest.push_str(&format!("{} {} {}\n", mode(&metadata), name, sha256::hash(&contents).as_ref().to_hex()));
+        }
+    }
+    Ok(format!("sha256:{}", sha256::hash(manifest.as_bytes()).as_ref().to_hex()))
+}
+
+impl Lockfile {
+    pub fn new() -> Lockfile {
+        Lockfile{packages: BTreeMap::new()}
+    }
+
+    pub fn from_executables(executables: &[Executable]) -> io::Result<Lockfile> {
+        let mut lockfile = Lockfile::new();
+        for exe in executables {
+            lockfile.packages.insert(exe.name.clone(), LockedPackage{version: exe.version.clone(),
+                                                                     hash: try!(hash_dir(&exe.dir))});
+        }
+        Ok(lockfile)
+    }
+
+    // A missing lockfile isn't an error, it just hasn't been written yet.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Option<Lockfile>> {
+        let path = path.as_ref();
+        let mut f = match File::open(path) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(_) => return Err(Error::new(PathBuf::from(path), "failure to open"))
+        };
+        let mut s = String::new();
+        if f.read_to_string(&mut s).is_err() {
+            return Err(Error::new(PathBuf::from(path), "failure to read - perhaps invalid UTF-8?"));
+        }
+        Lockfile::from_str(path, &s).map(Some)
+    }
+
+    pub fn from_str<P: AsRef<Path>>(path: P, s: &str) -> Result<Lockfile> {
+        let path = path.as_ref();
+        let config: toml::Value = match s.parse() {
+            Ok(v) => v,
+            Err(_) => return Err(Error::new(PathBuf::from(path), "failure to parse toml"))
+        };
+
+        let mut lockfile = Lockfile::new();
+        let packages = match config.lookup("package") {
+            Some(packages) => match packages.as_slice() {
+                Some(slice) => slice,
+                None => return Err(Error::new(PathBuf::from(path), "`package` isn't an array of tables."))
+            },
+            None => return Ok(lockfile)
+        };
+
+        for package in packages {
+            let field = |key: &str| -> Result<String> {
+                match package.lookup(key).and_then(|v| v.as_str()) {
+                    Some(v) => Ok(v.to_string()),
+                    None => Err(Error::new(PathBuf::from(path), &format!("package `{}` is missing or isn't a string.", key)))
+                }
+            };
+            lockfile.packages.insert(try!(field("name")), LockedPackage{version: try!(field("version")),
+                                                                        hash: try!(field("hash"))});
+        }
+
+        Ok(lockfile)
+    }
+
+    pub fn versions(&self) -> BTreeMap<String, String> {
+        self.packages.iter().map(|(name, locked)| (name.clone(), locked.version.clone())).collect()
+    }
+
+    // Everything in `current` that doesn't match what's locked, worded for
+    // people. Packages that are only in the lockfile don't count; they may
+    // belong to roles that weren't compiled this time.
+    pub fn differences(&self, current: &Lockfile) -> Vec<String> {
+        let mut v = Vec::new();
+        for (name, package) in current.packages.iter() {
+            match self.packages.get(name) {
+                None => v.push(format!("{} {} isn't in {}", name, package.version, FILENAME)),
+                Some(locked) if locked.version != package.version => {
+                    v.push(format!("{} resolved to {} but {} has {}", name, package.version, FILENAME, locked.version))
+                },
+                Some(locked) if locked.hash != package.hash => {
+                    v.push(format!("{} {} has changed since {} was written", name, package.version, FILENAME))
+                },
+                _ => {}
+            }
+        }
+        v
+    }
+
+    // Packages in `other` replace the ones here.
+    pub fn merge(&mut self, other: &Lockfile) {
+        for (name, package) in other.packages.iter() {
+            self.packages.insert(name.clone(), package.clone());
+        }
+    }
+}
+
+impl fmt::Display for Lockfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "# Written by `tsh compile`. Check it in alongside your roles."));
+        for (name, package) in self.packages.iter() {
+            try!(write!(f, "\n[[package]]\nname = {}\nversion = {}\nhash = {}\n",
+                        toml::Value::String(name.clone()),
+                        toml::Value::String(package.version.clone()),
+                        toml::Value::String(package.hash.clone())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_dir, LockedPackage, Lockfile};
+    use sodiumoxide;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use tempdir::TempDir;
+
+    fn locked(version: &str, hash: &str) -> LockedPackage {
+        LockedPackage{version: version.to_string(), hash: hash.to_string()}
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut lockfile = Lockfile::new();
+        lockfile.packages.insert("nginx".to_string(), locked("1.2.0", "sha256:abc"));
+        lockfile.packages.insert("app".to_string(), locked("17", "sha256:def"));
+
+        let s = lockfile.to_string();
+        assert_eq!(Lockfile::from_str("turboshell.lock", &s).unwrap(), lockfile);
+        assert_eq!(Lockfile::from_str("turboshell.lock", "").unwrap(), Lockfile::new());
+
+        let err = Lockfile::from_str("turboshell.lock", "[[package]]\nname = \"a\"\n").err().unwrap();
+        assert_eq!(err.message(), "package `version` is missing or isn't a string.");
+    }
+
+    #[test]
+    fn differences() {
+        let mut old = Lockfile::new();
+        old.packages.insert("same".to_string(), locked("1.0.0", "sha256:1"));
+        old.packages.insert("upgraded".to_string(), locked("1.0.0", "sha256:2"));
+        old.packages.insert("edited".to_string(), locked("1.0.0", "sha256:3"));
+        old.packages.insert("other_role".to_string(), locked("1.0.0", "sha256:4"));
+
+        let mut new = Lockfile::new();
+        new.packages.insert("same".to_string(), locked("1.0.0", "sha256:1"));
+        new.packages.insert("upgraded".to_string(), locked("2.0.0", "sha256:2"));
+        new.packages.insert("edited".to_string(), locked("1.0.0", "sha256:changed"));
+        new.packages.insert("added".to_string(), locked("1.0.0", "sha256:5"));
+
+        assert_eq!(old.differences(&new),
+                   vec!["added 1.0.0 isn't in turboshell.lock",
+                        "edited 1.0.0 has changed since turboshell.lock was written",
+                        "upgraded resolved to 2.0.0 but turboshell.lock has 1.0.0"]);
+        assert!(old.differences(&old).is_empty());
+    }
+
+    #[test]
+    fn hash_changes_with_contents() {
+        if ! sodiumoxide::init() {
+            panic!("Failed to init libsodium");
+        }
+
+        let dir = TempDir::new("lockfile").unwrap();
+        fs::File::create(dir.path().join("main.sh")).unwrap().write_all(b"echo one\n").unwrap();
+        let before = hash_dir(dir.path()).unwrap();
+        assert_eq!(hash_dir(dir.path()).unwrap(), before);
+
+        // hidden files aren't archived, so they don't count
+        fs::File::create(dir.path().join(".swp")).unwrap().write_all(b"junk").unwrap();
+        assert_eq!(hash_dir(dir.path()).unwrap(), before);
+
+        // so do permissions, but only the executable bit
+        fs::set_permissions(dir.path().join("main.sh"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(hash_dir(dir.path()).unwrap(), before);
+        fs::set_permissions(dir.path().join("main.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        let executable = hash_dir(dir.path()).unwrap();
+        assert!(executable != before);
+
+        fs::File::create(dir.path().join("main.sh")).unwrap().write_all(b"echo two\n").unwrap();
+        assert!(hash_dir(dir.path()).unwrap() != executable);
+    }
+}
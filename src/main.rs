@@ -5,23 +5,43 @@ extern crate sodiumoxide;
 extern crate libc;
 
 use std::io;
+use std::io::Write;
 
 const USAGE: &'static str = "
 Turboshell
 
 Usage:
   tsh keytool [ -o FILE | <seedfile> ]
-  tsh compile [ -d DIR ] [ -o FILE ] -s FILE <role>...
+  tsh compile [ -d DIR ] [ -o FILE ] [ --expires-in=DURATION ] [ --self-contained | --format=FORMAT ] [ --locked ] [ --env-conflicts=HOW ] -s FILE <role>...
   tsh inspect [ -o FILE ] -k KEY [ <archive> ]
-  tsh run -k KEY [ <archive> ]
+  tsh inspect --plan -k KEY [ <archive> ]
+  tsh run [ --action=ACTION ] -k KEY [ <archive> ]
+  tsh run [ --action=ACTION ] -d DIR
+  tsh verify [ --json ] -k KEY [ <archive> ]
+  tsh diff -k KEY <old> <new>
+  tsh extract [ --force ] -k KEY [ <archive> ] -d DIR
+  tsh facts [ --json ]
+  tsh graph [ --json ] -d DIR <role>...
+  tsh graph [ --json ] -k KEY [ <archive> ]
+  tsh why -d DIR --roles=ROLES <package>
   tsh --help
   tsh --version
 
 Options:
   -s FILE, --seedfile=FILE  PK seed file
   -k KEY, --key=KEY         public key
-  -d DIR, --directory=DIR   directory [default: ./]
+  -d DIR, --directory=DIR   directory (compile defaults to ./)
   -o FILE, --output=FILE    output file
+  --expires-in=DURATION     refuse to run the archive after DURATION (e.g. 12h, 7d)
+  --json                    print a machine-readable report
+  --force                   extract into a directory that isn't empty
+  --self-contained          bundle the tsh binary with the archive
+  --format=FORMAT           compile to a `tsar` archive or an `sh` script [default: tsar]
+  --locked                  fail if turboshell.lock is missing or out of date
+  --env-conflicts=HOW       `merge`, `error` or `per-role` when roles set a package's env differently [default: merge]
+  --action=ACTION           run this action of every package instead of main
+  --plan                    show what run would do on this host
+  --roles=ROLES             comma separated roles
   -h, --help                print this help message
   -V, --version             print the version of this program
 ";
@@ -31,27 +51,100 @@ struct Args {
     arg_archive: String,
     arg_seedfile: String,
     arg_role: Vec<String>,
+    arg_old: String,
+    arg_new: String,
+    arg_package: String,
     cmd_keytool: bool,
     cmd_compile: bool,
     cmd_inspect: bool,
     cmd_run: bool,
+    cmd_verify: bool,
+    cmd_diff: bool,
+    cmd_extract: bool,
+    cmd_facts: bool,
+    cmd_graph: bool,
+    cmd_why: bool,
     flag_seedfile: String,
     flag_key: String,
     flag_directory: String,
     flag_output: String,
+    flag_expires_in: String,
+    flag_json: bool,
+    flag_force: bool,
+    flag_self_contained: bool,
+    flag_format: String,
+    flag_locked: bool,
+    flag_env_conflicts: String,
+    flag_action: String,
+    flag_plan: bool,
+    flag_roles: String,
+    flag_version: bool,
+}
+
+const BUNDLE_USAGE: &'static str = "
+Self-contained Turboshell archive
+
+Usage:
+  archive [ --action=ACTION ] [ -k KEY ]
+  archive --help
+  archive --version
+
+Options:
+  -k KEY, --key=KEY         verify against this public key instead of the built in one
+  --action=ACTION           run this action of every package instead of main
+  -h, --help                print this help message
+  -V, --version             print the version of this program
+";
+
+#[derive(Debug,RustcDecodable)]
+struct BundleArgs {
+    flag_action: String,
+    flag_key: String,
     flag_version: bool,
 }
 
 include!(concat!(env!("OUT_DIR"), "/BUILD.rs"));
 
+fn print_version() {
+    println!("turboshell {}-{} built on {}", env!("CARGO_PKG_VERSION"), BUILD_GIT_SHA, BUILD_DATETIME);
+    println!("libsodium {}", sodiumoxide::version::version_string());
+}
+
+// When this binary has an archive attached to it, run that instead of
+// behaving like tsh.
+fn bundled_archive() -> Option<turboshell::bundle::Bundle> {
+    std::env::current_exe().ok()
+        .and_then(|exe| turboshell::bundle::from_path(exe).ok())
+        .and_then(|bundle| bundle)
+}
+
 fn main() {
+    if let Some(bundle) = bundled_archive() {
+        let args: BundleArgs = docopt::Docopt::new(BUNDLE_USAGE)
+            .and_then(|d| d.decode())
+            .unwrap_or_else(|e| e.exit());
+
+        if args.flag_version {
+            print_version();
+        } else {
+            if ! sodiumoxide::init() {
+                panic!("Failed to init libsodium");
+            }
+
+            let exit_code = turboshell::commands::run_bundled(&mut io::stdout(), args.flag_action, args.flag_key, bundle);
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        return;
+    }
+
     let args: Args = docopt::Docopt::new(USAGE)
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
     if args.flag_version {
-        println!("turboshell {}-{} built on {}", env!("CARGO_PKG_VERSION"), BUILD_GIT_SHA, BUILD_DATETIME);
-        println!("libsodium {}", sodiumoxide::version::version_string());
+        print_version();
     } else {
         if ! sodiumoxide::init() {
             panic!("Failed to init libsodium");
@@ -62,11 +155,50 @@ fn main() {
         let exit_code = if args.cmd_keytool {
             turboshell::commands::keytool(&mut out, args.arg_seedfile, args.flag_output)
         } else if args.cmd_compile {
-            turboshell::commands::compile(&mut out, args.flag_directory, args.flag_output, args.flag_seedfile, args.arg_role)
+            let format = match args.flag_format.as_str() {
+                "tsar" if args.flag_self_contained => turboshell::commands::CompileFormat::SelfContained,
+                "tsar" => turboshell::commands::CompileFormat::Archive,
+                "sh" => turboshell::commands::CompileFormat::Shell,
+                other => {
+                    writeln!(&mut io::stderr(), "Unknown format {}: expected tsar or sh", other).unwrap();
+                    std::process::exit(1);
+                }
+            };
+            let env_conflicts = match turboshell::resolve::EnvConflicts::parse(&args.flag_env_conflicts) {
+                Some(how) => how,
+                None => {
+                    writeln!(&mut io::stderr(), "Unknown env conflicts {}: expected merge, error or per-role", args.flag_env_conflicts).unwrap();
+                    std::process::exit(1);
+                }
+            };
+            let mut options = turboshell::commands::CompileOptions{output: args.flag_output,
+                                                                   seedfile: args.flag_seedfile,
+                                                                   expires_in: args.flag_expires_in,
+                                                                   format: format,
+                                                                   locked: args.flag_locked,
+                                                                   env_conflicts: env_conflicts,
+                                                                   roles: args.arg_role,
+                                                                   ..Default::default()};
+            if args.flag_directory != "" {
+                options.basedir = args.flag_directory;
+            }
+            turboshell::commands::compile(&mut out, options)
         } else if args.cmd_inspect {
-            turboshell::commands::inspect(&mut out, args.flag_output, args.flag_key, args.arg_archive)
+            turboshell::commands::inspect(&mut out, args.flag_plan, args.flag_output, args.flag_key, args.arg_archive)
         } else if args.cmd_run {
-            turboshell::commands::run(&mut out, args.flag_key, args.arg_archive)
+            turboshell::commands::run(&mut out, args.flag_action, args.flag_key, args.arg_archive, args.flag_directory)
+        } else if args.cmd_verify {
+            turboshell::commands::verify(&mut out, args.flag_json, args.flag_key, args.arg_archive)
+        } else if args.cmd_diff {
+            turboshell::commands::diff(&mut out, args.flag_key, args.arg_old, args.arg_new)
+        } else if args.cmd_extract {
+            turboshell::commands::extract(&mut out, args.flag_force, args.flag_key, args.arg_archive, args.flag_directory)
+        } else if args.cmd_facts {
+            turboshell::commands::facts(&mut out, args.flag_json)
+        } else if args.cmd_graph {
+            turboshell::commands::graph(&mut out, args.flag_json, args.flag_key, args.arg_archive, args.flag_directory, args.arg_role)
+        } else if args.cmd_why {
+            turboshell::commands::why(&mut out, args.flag_directory, args.flag_roles, args.arg_package)
         } else {
             unreachable!()
         };
@@ -0,0 +1,182 @@
+use chrono::{TimeZone, UTC};
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml;
+
+use error::{Error, Result};
+use resolve::EnvConflicts;
+
+// Timestamps in archive.toml are seconds since the unix epoch.
+pub fn now() -> i64 {
+    UTC::now().timestamp()
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    UTC.timestamp(timestamp, 0).to_rfc3339()
+}
+
+pub fn is_expired(expires: Option<i64>, now: i64) -> bool {
+    match expires {
+        Some(expires) => expires <= now,
+        None => false
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Metadata {
+    pub roles: Vec<String>,
+    pub role_packages: BTreeMap<String, BTreeMap<String, String>>,
+    pub created: Option<i64>,
+    pub expires: Option<i64>,
+    pub env_conflicts: EnvConflicts,
+}
+
+fn lookup_timestamp(path: &Path, config: &toml::Value, key: &str) -> Result<Option<i64>> {
+    match config.lookup(&format!("archive.{}", key)) {
+        Some(value) => match value.as_integer() {
+            Some(i) => Ok(Some(i)),
+            None => Err(Error::new(PathBuf::from(path), &format!("`{}` isn't an integer.", key)))
+        },
+        None => Ok(None)
+    }
+}
+
+impl Metadata {
+    pub fn from_str<P: AsRef<Path>>(path: P, s: &str) -> Result<Metadata> {
+        match s.parse() {
+            Ok(config) => Metadata::from_toml(path, &config),
+            Err(_) => Err(Error::new(PathBuf::from(path.as_ref()), "failure to parse toml"))
+        }
+    }
+
+    pub fn from_toml<P: AsRef<Path>>(path: P, config: &toml::Value) -> Result<Metadata> {
+        let path = path.as_ref();
+
+        let roles = match config.lookup("archive.roles") {
+            Some(roles) => {
+                match roles.as_slice() {
+                    Some(slice) => {
+                        let mut v = Vec::with_capacity(slice.len());
+                        for name in slice {
+                            match name.as_str() {
+                                Some(str) => v.push(str.to_string()),
+                                None => return Err(Error::new(PathBuf::from(path), "`roles` isn't an array of strings."))
+                            };
+                        }
+                        v
+                    },
+                    None => return Err(Error::new(PathBuf::from(path), "`roles` key isn't an array."))
+                }
+            },
+            None => vec![]
+        };
+
+        let mut role_packages = BTreeMap::new();
+        if let Some(table) = config.lookup("role").and_then(|t| t.as_table()) {
+            for (role, packages) in table {
+                let mut m = BTreeMap::new();
+                if let Some(packages) = packages.as_table() {
+                    for (name, version) in packages {
+                        if let Some(version) = version.as_str() {
+                            m.insert(name.clone(), version.to_string());
+                        }
+                    }
+                }
+                role_packages.insert(role.clone(), m);
+            }
+        }
+
+        // archives from before `env_conflicts` merged, more or less
+        let env_conflicts = match config.lookup("archive.env_conflicts") {
+            Some(value) => match value.as_str().and_then(EnvConflicts::parse) {
+                Some(how) => how,
+                None => return Err(Error::new(PathBuf::from(path), "`env_conflicts` isn't \"merge\", \"error\" or \"per-role\"."))
+            },
+            None => EnvConflicts::Merge
+        };
+
+        Ok(Metadata{ roles: roles,
+                     role_packages: role_packages,
+                     created: try!(lookup_timestamp(path, config, "created")),
+                     expires: try!(lookup_timestamp(path, config, "expires")),
+                     env_conflicts: env_conflicts })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        is_expired(self.expires, now())
+    }
+}
+
+impl ToJson for Metadata {
+    fn to_json(&self) -> Json {
+        let mut m = BTreeMap::new();
+        m.insert("roles".to_string(), self.roles.to_json());
+        m.insert("packages".to_string(), self.role_packages.to_json());
+        m.insert("created".to_string(), self.created.to_json());
+        m.insert("expires".to_string(), self.expires.to_json());
+        m.insert("env_conflicts".to_string(), self.env_conflicts.name().to_json());
+        Json::Object(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_expired, Metadata};
+    use resolve::EnvConflicts;
+
+    #[test]
+    fn metadata_from_str() {
+        let m = Metadata::from_str("archive.toml", r#"
+[archive]
+roles = ["first", "second"]
+created = 100
+expires = 200
+env_conflicts = "per-role"
+
+[role.first]
+a = "17"
+
+[role.second]
+b = "17"
+"#).unwrap();
+        assert_eq!(m.roles, vec!["first".to_string(), "second".to_string()]);
+        assert_eq!(m.role_packages["first"]["a"], "17");
+        assert_eq!(m.role_packages["second"]["b"], "17");
+        assert_eq!(m.created, Some(100));
+        assert_eq!(m.expires, Some(200));
+        assert_eq!(m.env_conflicts, EnvConflicts::PerRole);
+    }
+
+    #[test]
+    fn metadata_without_timestamps() {
+        let m = Metadata::from_str("archive.toml", "[archive]\nroles = []\n").unwrap();
+        assert_eq!(m.created, None);
+        assert_eq!(m.expires, None);
+        assert!(!m.is_expired());
+        assert_eq!(m.env_conflicts, EnvConflicts::Merge);
+    }
+
+    #[test]
+    fn metadata_errors() {
+        let err = Metadata::from_str("archive.toml", "[archive\n").err().unwrap();
+        assert_eq!(err.message(), "failure to parse toml");
+
+        let err = Metadata::from_str("archive.toml", "[archive]\nroles = \"uh oh\"\n").err().unwrap();
+        assert_eq!(err.message(), "`roles` key isn't an array.");
+
+        let err = Metadata::from_str("archive.toml", "[archive]\nexpires = \"tomorrow\"\n").err().unwrap();
+        assert_eq!(err.message(), "`expires` isn't an integer.");
+
+        let err = Metadata::from_str("archive.toml", "[archive]\nenv_conflicts = \"first\"\n").err().unwrap();
+        assert_eq!(err.message(), "`env_conflicts` isn't \"merge\", \"error\" or \"per-role\".");
+    }
+
+    #[test]
+    fn expiry() {
+        assert!(!is_expired(None, 100));
+        assert!(!is_expired(Some(101), 100));
+        assert!(is_expired(Some(100), 100));
+        assert!(is_expired(Some(99), 100));
+    }
+}
@@ -1,9 +1,15 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir, WalkDirIterator};
 
+use condition;
+use condition::Condition;
 use error::{Error, Result};
+use facts::Facts;
 use runlist::{Env, Package, Role};
+use template;
+use version;
 
 struct Stack<T> {
     level: usize,
@@ -45,6 +51,12 @@ impl<T> Stack<T> {
         self.vec[self.level].as_slice()
     }
 
+    // The saved values of every level above this one, outermost first. While
+    // sorting those are the packages whose dependencies are being visited.
+    fn ancestors(&self) -> Vec<&T> {
+        self.saved[..self.level].iter().filter_map(|saved| saved.front()).collect()
+    }
+
     fn at_top(&self) -> bool {
         self.level == 0
     }
@@ -71,88 +83,883 @@ impl<T> Stack<T> {
 pub struct Executable {
     pub dir: PathBuf,
     pub name: String,
+    pub version: String,
     pub main: PathBuf,
+    pub check: Option<PathBuf>,
+    pub actions: BTreeMap<String, PathBuf>,
+    pub templates: BTreeMap<PathBuf, PathBuf>,
     pub env: Env,
+    pub dependencies: Vec<String>,
+}
+
+// What a role sets for a package: its `[env.<package>]` table, and whatever
+// else in its `env` the package declares.
+fn overrides(role: &Role, package: &Package) -> Env {
+    let mut env = role.env().iter()
+        .filter(|&(k, _)| package.declares(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Env>();
+    env.extend(role.package_env(&package.name).cloned().unwrap_or_default());
+    env
 }
 
 impl Executable {
-    fn from_role_and_package(role: &Role, package: &Package) -> Executable {
+    fn from_role_and_package(role: &Role, package: &Package, facts: Option<&Facts>) -> Result<Executable> {
+        let overrides = overrides(role, package);
+        let set_by = role.env().keys().chain(overrides.keys())
+            .map(|k| (k.clone(), role.path.clone()))
+            .collect::<BTreeMap<String, PathBuf>>();
+        Executable::from_env(role, role.env(), &overrides, &set_by, package, facts)
+    }
+
+    // `overrides` go on top of the package's env, and `role_env` is what else
+    // its values can refer to. Errors about a var point at the role file in
+    // `set_by`, if a role set it.
+    fn from_env(role: &Role, role_env: &Env, overrides: &Env, set_by: &BTreeMap<String, PathBuf>, package: &Package, facts: Option<&Facts>) -> Result<Executable> {
         let mut env = package.env.clone();
-        for (k, v) in role.env() {
-            // only allow overrides of vars that already exist
-            if env.contains_key(k) {
-                env.insert(k.clone(), v.clone());
+        env.extend(overrides.clone());
+        let path_of = |name: &str| set_by.get(name).cloned().unwrap_or_else(|| package.dir.join("package.toml"));
+
+        // values can refer to each other, to the role's env and to a few
+        // built-in vars with `${NAME}`
+        let mut builtins = Env::new();
+        builtins.insert("TSH_ROLE".to_string(), role.name.clone());
+        builtins.insert("TSH_PACKAGE".to_string(), package.name.clone());
+        builtins.insert("TSH_PACKAGE_VERSION".to_string(), package.version().to_string());
+        let env = match template::expand_env(&env, &[&env, role_env, &builtins]) {
+            Ok(v) => v,
+            Err((name, message)) => {
+                return Err(Error::new(path_of(&name), &format!("env \"{}\" of package {} {}", name, package.name, message)));
+            }
+        };
+
+        for (name, var) in package.schema.iter() {
+            match env.get(name) {
+                Some(value) => if let Err(e) = var.validate(value) {
+                    let path = path_of(name);
+                    return Err(Error::new(path, &format!("env \"{}\" of package {} is \"{}\", which {}.", name, package.name, value, e)));
+                },
+                None => if var.required {
+                    let description = var.description.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default();
+                    return Err(Error::new(role.path.clone(), &format!("package {} requires env \"{}\"{}, which role {} doesn't set.",
+                                                                      package.name, name, description, role.name)));
+                }
             }
         }
 
-        Executable { dir: package.dir.clone(),
-                     name: package.name.clone(),
-                     main: package.main.clone(),
-                     env: env }
+        Ok(Executable { dir: package.dir.clone(),
+                        name: package.name.clone(),
+                        version: package.version().to_string(),
+                        main: package.main.clone(),
+                        check: package.check.clone(),
+                        actions: package.actions.clone(),
+                        templates: package.templates.clone(),
+                        env: env,
+                        dependencies: package.dependencies_on(facts).into_iter().map(|name| name.to_string()).collect() })
+    }
+}
+
+// A dependency's `when`, and whether it held on the host. See
+// `PackageRepository::conditions`.
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    // "role web" or "package app 1.0.0"
+    pub from: String,
+    pub name: String,
+    pub condition: Condition,
+    pub holds: bool,
+}
+
+// A dependency in a `Graph`, on the package that will be used for it, with
+// its `when`, if any.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edge {
+    pub name: String,
+    pub condition: Option<Condition>,
+}
+
+// An edge on the package that stands in for `name`, if it's virtual.
+fn edge(providers: &HashMap<&str, &str>, name: &str, condition: Option<&Condition>) -> Edge {
+    Edge{name: providers.get(name).cloned().unwrap_or(name).to_string(), condition: condition.cloned()}
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node {
+    pub name: String,
+    pub version: String,
+    pub dependencies: Vec<Edge>,
+}
+
+// What uses what: each role with the packages it depends on directly, and
+// every package with its own dependencies, in the order they'd run. See
+// `PackageRepository::graph` and `PackageRepository::repository_graph`.
+#[derive(Debug, PartialEq)]
+pub struct Graph {
+    pub roles: Vec<(String, Vec<Edge>)>,
+    pub packages: Vec<Node>,
+    // where each package is in `packages`
+    index: BTreeMap<String, usize>,
+}
+
+impl Graph {
+    pub fn new(roles: Vec<(String, Vec<Edge>)>, packages: Vec<Node>) -> Graph {
+        let index = packages.iter().enumerate().map(|(i, node)| (node.name.clone(), i)).collect();
+        Graph{roles: roles, packages: packages, index: index}
+    }
+
+    pub fn package(&self, name: &str) -> Option<&Node> {
+        self.index.get(name).map(|&i| &self.packages[i])
+    }
+
+    pub fn dependencies(&self, name: &str) -> Vec<&str> {
+        self.package(name)
+            .map(|node| node.dependencies.iter().map(|edge| edge.name.as_str()).collect())
+            .unwrap_or_default()
+    }
+
+    // The packages that depend on this one directly, in run order.
+    pub fn dependents(&self, name: &str) -> Vec<&str> {
+        self.packages.iter()
+            .filter(|node| node.dependencies.iter().any(|edge| edge.name == name))
+            .map(|node| node.name.as_str())
+            .collect()
+    }
+
+    // Everything a package needs, directly or not, in run order.
+    pub fn closure(&self, name: &str) -> Vec<&str> {
+        let mut needed = HashSet::new();
+        let mut todo = self.dependencies(name);
+        while let Some(name) = todo.pop() {
+            if needed.insert(name) {
+                todo.extend(self.dependencies(name));
+            }
+        }
+        self.order().into_iter().filter(|name| needed.contains(name)).collect()
+    }
+
+    // The packages are already after their dependencies.
+    pub fn order(&self) -> Vec<&str> {
+        self.packages.iter().map(|node| node.name.as_str()).collect()
+    }
+
+    // Just these packages and everything they need, with the roles that
+    // depend on any of them.
+    pub fn subgraph(&self, names: &[&str]) -> Graph {
+        let mut keep = HashSet::new();
+        for name in names.iter().filter(|name| self.package(name).is_some()) {
+            keep.insert(*name);
+            keep.extend(self.closure(name));
+        }
+
+        let roles = self.roles.iter()
+            .map(|&(ref role, ref edges)| (role.clone(), edges.iter().filter(|edge| keep.contains(edge.name.as_str())).cloned().collect::<Vec<Edge>>()))
+            .filter(|&(_, ref edges)| ! edges.is_empty())
+            .collect();
+        let packages = self.packages.iter().filter(|node| keep.contains(node.name.as_str())).cloned().collect();
+        Graph::new(roles, packages)
+    }
+
+    // Ways the roles get to a package: the role, then each dependency
+    // followed from it, ending with the package. There can be a great many
+    // of them, so this stops after `limit`.
+    pub fn paths(&self, name: &str, limit: usize) -> Vec<(&str, Vec<&Edge>)> {
+        // only follow dependencies that lead to the package at all
+        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for node in self.packages.iter() {
+            for edge in node.dependencies.iter() {
+                dependents.entry(edge.name.as_str()).or_insert_with(Vec::new).push(node.name.as_str());
+            }
+        }
+        let mut leads = HashSet::new();
+        let mut todo = vec![name];
+        while let Some(name) = todo.pop() {
+            if leads.insert(name) {
+                todo.extend(dependents.get(name).into_iter().flat_map(|v| v.iter().cloned()));
+            }
+        }
+
+        fn visit<'a>(graph: &'a Graph, edge: &'a Edge, name: &str, leads: &HashSet<&str>, limit: usize, path: &mut Vec<&'a Edge>, paths: &mut Vec<Vec<&'a Edge>>) {
+            if paths.len() >= limit || ! leads.contains(edge.name.as_str()) {
+                return;
+            }
+            path.push(edge);
+            if edge.name == name {
+                paths.push(path.clone());
+            } else if let Some(node) = graph.package(&edge.name) {
+                for dep in node.dependencies.iter() {
+                    visit(graph, dep, name, leads, limit, path, paths);
+                }
+            }
+            path.pop();
+        }
+
+        let mut v = Vec::new();
+        for &(ref role, ref edges) in self.roles.iter() {
+            let mut paths = Vec::new();
+            for edge in edges {
+                visit(self, edge, name, &leads, limit - v.len(), &mut Vec::new(), &mut paths);
+            }
+            v.extend(paths.into_iter().map(|path| (role.as_str(), path)));
+        }
+        v
+    }
+}
+
+// What to do with a package that's shared by roles which set different
+// values for its env.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvConflicts {
+    // run it once, with the value from the first role that sets each var
+    Merge,
+    // refuse to resolve
+    Error,
+    // run it once for each role, unless the env comes out the same
+    PerRole,
+}
+
+impl EnvConflicts {
+    pub fn parse(s: &str) -> Option<EnvConflicts> {
+        match s {
+            "merge" => Some(EnvConflicts::Merge),
+            "error" => Some(EnvConflicts::Error),
+            "per-role" => Some(EnvConflicts::PerRole),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EnvConflicts::Merge => "merge",
+            EnvConflicts::Error => "error",
+            EnvConflicts::PerRole => "per-role",
+        }
+    }
+}
+
+// An env var of a shared package that its roles set to different values.
+#[derive(Debug, PartialEq)]
+pub struct EnvConflict {
+    pub package: String,
+    pub name: String,
+    // each role that sets it, in order, with the value it comes out as
+    pub values: Vec<(String, String)>,
+    // of the first role that disagrees with the first one
+    pub path: PathBuf,
+}
+
+impl fmt::Display for EnvConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values = self.values.iter()
+            .map(|&(ref role, ref value)| format!("\"{}\" in role {}", value, role))
+            .collect::<Vec<String>>();
+        write!(f, "env \"{}\" of package {} is {}", self.name, self.package, values.join(" but "))
+    }
+}
+
+// Something that asked for a package. Kept around to explain conflicts.
+struct Requirement<'a> {
+    name: &'a str,
+    version_req: &'a str,
+    from: String,
+    path: PathBuf,
+    // the virtual package it asked for, which `name` provides
+    provides: Option<&'a str>,
+}
+
+impl<'a> Requirement<'a> {
+    // Asking for a virtual package means asking for its provider.
+    fn new(name: &'a str, version_req: &'a str, from: String, path: PathBuf, providers: &HashMap<&'a str, &'a str>) -> Result<Requirement<'a>> {
+        match providers.get(name) {
+            Some(provider) => {
+                if version_req.trim() != "*" {
+                    return Err(Error::new(path, &format!("{} depends on {} \"{}\", but {} is a virtual package, so it has no versions", from, name, version_req, name)));
+                }
+                Ok(Requirement{name: provider, version_req: version_req, from: from, path: path, provides: Some(name)})
+            },
+            None => Ok(Requirement{name: name, version_req: version_req, from: from, path: path, provides: None})
+        }
+    }
+
+    fn allows(&self, package: &Package) -> bool {
+        version::matches(self.version_req, package.version()) &&
+            self.provides.map(|v| package.provides.iter().any(|p| p == v)).unwrap_or(true)
+    }
+}
+
+// See `PackageRepository::select`.
+struct Choice<'a> {
+    name: &'a str,
+    // the first requirement on it; every one before that has been picked
+    first: usize,
+    candidates: Vec<&'a Package>,
+    tried: usize,
+    len: usize,
+    first_error: Option<Error>,
+}
+
+impl<'a> Choice<'a> {
+    fn fail(&mut self, error: Error) {
+        if self.first_error.is_none() {
+            self.first_error = Some(error);
+        }
+    }
+
+    // Why none of the candidates fit.
+    fn error(self, repo: &PackageRepository, requirements: &[Requirement]) -> Error {
+        let name = self.name;
+        self.first_error.unwrap_or_else(|| repo.conflict(name, requirements, &repo.packages[name]))
     }
 }
 
 #[derive(Debug)]
 pub struct PackageRepository {
-    deps: HashMap<Package, Vec<Package>>,
+    basedir: PathBuf,
+    // every version of every package, newest first
+    packages: BTreeMap<String, Vec<Package>>,
+    // versions to try before the newest one, usually from turboshell.lock
+    preferred: BTreeMap<String, String>,
+    env_conflicts: EnvConflicts,
+    // directories under basedir that weren't loaded, and why for those that
+    // couldn't be
+    skipped: Vec<PathBuf>,
+    warnings: Vec<Error>,
+}
+
+// What each of the roles sets for a package, as written, in role order.
+fn role_values<'a>(roles: &[&'a Role], package: &Package) -> BTreeMap<String, Vec<(&'a Role, String)>> {
+    let mut values: BTreeMap<String, Vec<(&Role, String)>> = BTreeMap::new();
+    for role in roles.iter() {
+        for (k, v) in overrides(role, package) {
+            values.entry(k).or_insert_with(Vec::new).push((role, v));
+        }
+    }
+    values
+}
+
+// The vars of a package that several roles set to different values.
+fn env_conflicts_between(roles: &[&Role], package: &Package) -> Vec<EnvConflict> {
+    let mut conflicts = Vec::new();
+    for (k, v) in role_values(roles, package) {
+        if let Some(&(role, _)) = v.iter().find(|&&(_, ref value)| *value != v[0].1) {
+            conflicts.push(EnvConflict{package: package.name.clone(),
+                                       name: k,
+                                       values: v.iter().map(|&(role, ref value)| (role.name.clone(), value.clone())).collect(),
+                                       path: role.path.clone()});
+        }
+    }
+    conflicts
+}
+
+// The executable of a package that several roles use, with the env of the
+// first of them plus whatever the others set that it doesn't. The roles'
+// values are merged as written, and only then expanded and checked, so
+// `${PORT}` means the same PORT the package ends up with.
+fn shared_executable(roles: &[&Role], package: &Package, facts: Option<&Facts>) -> Result<Executable> {
+    let mut role_env = Env::new();
+    let mut set_by = BTreeMap::new();
+    for role in roles.iter() {
+        for (k, v) in role.env() {
+            role_env.entry(k.clone()).or_insert_with(|| v.clone());
+            set_by.entry(k.clone()).or_insert_with(|| role.path.clone());
+        }
+    }
+
+    let mut merged = Env::new();
+    for (k, v) in role_values(roles, package) {
+        set_by.insert(k.clone(), v[0].0.path.clone());
+        merged.insert(k, v[0].1.clone());
+    }
+
+    Executable::from_env(roles[0], &role_env, &merged, &set_by, package, facts)
+}
+
+// Names the packages in the cycle, and where they're all defined. An edge
+// that's only there because of `after` or `before` is marked as such.
+// `chain` starts and ends with the same package.
+fn cycle_error(role: &Role, chain: &[&Package], facts: Option<&Facts>) -> Error {
+    let mut message = format!("dependency cycle in role {}: {}", role.name, chain[0].name);
+    for pair in chain.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let how = if a.dependencies_on(facts).iter().any(|name| *name == b.name || b.provides.iter().any(|v| v == name)) {
+            ""
+        } else if a.after.iter().any(|name| *name == b.name || b.provides.contains(name)) {
+            " (after)"
+        } else {
+            " (before)"
+        };
+        message.push_str(&format!(" -> {}{}", b.name, how));
+    }
+    for package in chain[..chain.len() - 1].iter() {
+        message.push_str(&format!("\n  {}: {}", package.name, package.dir.join("package.toml").display()));
+    }
+    Error::new(role.path.clone(), &message)
 }
 
 impl PackageRepository {
     pub fn from_basedir<P: AsRef<Path>>(basedir: &P) -> Result<PackageRepository> {
-        fn is_dir(entry: &DirEntry) -> bool {
-            entry.file_type().is_dir()
+        let mut repo = try!(PackageRepository::empty(basedir));
+        repo.load_rest();
+        Ok(repo)
+    }
+
+    // Only the packages the roles can get to, which have to load. Everything
+    // else is skipped, unless a role needs a virtual package, since finding
+    // its providers means looking at every package.
+    pub fn for_roles<P: AsRef<Path>>(basedir: &P, roles: &Vec<Role>) -> Result<PackageRepository> {
+        let mut repo = try!(PackageRepository::empty(basedir));
+        let mut todo = roles.iter()
+            .flat_map(|role| role.dependencies().iter().map(|dep| dep.name.clone()))
+            .collect::<Vec<String>>();
+        let mut virtual_names = false;
+        while let Some(name) = todo.pop() {
+            if repo.packages.contains_key(&name) {
+                continue;
+            }
+            if ! repo.basedir.join(&name).is_dir() {
+                virtual_names = true;
+                continue;
+            }
+            let versions = try!(Package::versions(&repo.basedir, &name));
+            for package in versions.iter() {
+                todo.extend(package.dependencies().keys().cloned());
+            }
+            repo.packages.insert(name, versions);
         }
 
+        if virtual_names {
+            repo.load_rest();
+        } else {
+            let dirs = try!(repo.dirs());
+            repo.skipped = dirs.into_iter().filter(|dir| ! repo.loaded(dir)).collect();
+        }
+        Ok(repo)
+    }
+
+    fn empty<P: AsRef<Path>>(basedir: &P) -> Result<PackageRepository> {
         let dir = match basedir.as_ref().canonicalize() {
             Ok(d) => d,
             Err(_) => { return Err(Error::new(PathBuf::from(basedir.as_ref()), "directory doesn't exist")) }
         };
+        Ok(PackageRepository{basedir: dir,
+                             packages: BTreeMap::new(),
+                             preferred: BTreeMap::new(),
+                             env_conflicts: EnvConflicts::Merge,
+                             skipped: Vec::new(),
+                             warnings: Vec::new()})
+    }
 
-        let mut deps = HashMap::new();
-        let walker = WalkDir::new(&dir).max_depth(1).into_iter();
+    // Every directory a package could be in. roles/ and hidden ones, like
+    // .git/, never are.
+    fn dirs(&self) -> Result<Vec<PathBuf>> {
+        fn is_dir(entry: &DirEntry) -> bool {
+            entry.file_type().is_dir()
+        }
 
+        let mut dirs = Vec::new();
+        let walker = WalkDir::new(&self.basedir).min_depth(1).max_depth(1).into_iter();
         for entry in walker.filter_entry(|e| is_dir(e)) {
-            let entry = entry.unwrap();
-
-            if let Ok(package) = Package::from_file(&dir, entry.path().file_name().unwrap().to_str().unwrap()) {
-                let v = {
-                    let package_dependencies = package.dependencies();
-                    let mut v = Vec::with_capacity(package_dependencies.len());
-                    for (dep_name, _) in package_dependencies {
-                        // package_dependencies is a map of name => version
-                        // but version is locked to "local" right now
-                        v.push(try!(Package::from_file(&dir, dep_name)));
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => return Err(Error::new(self.basedir.clone(), "failure to read directory"))
+            };
+            let name = entry.file_name().to_string_lossy();
+            if name != "roles" && ! name.starts_with('.') {
+                dirs.push(entry.path().to_path_buf());
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    fn loaded(&self, dir: &Path) -> bool {
+        dir.file_name().and_then(|name| name.to_str()).map(|name| self.packages.contains_key(name)).unwrap_or(false)
+    }
+
+    // Load whatever isn't loaded yet. A package that's broken is only an
+    // error if something depends on it, so for now it's a warning; see
+    // `versions`.
+    fn load_rest(&mut self) {
+        let dirs = match self.dirs() {
+            Ok(dirs) => dirs,
+            Err(e) => {
+                self.warnings.push(e);
+                return;
+            }
+        };
+        let dirs = dirs.into_iter().filter(|dir| ! self.loaded(dir)).collect::<Vec<PathBuf>>();
+        for dir in dirs {
+            let name = match dir.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => {
+                    self.skipped.push(dir);
+                    continue;
+                }
+            };
+            match Package::versions(&self.basedir, &name) {
+                Ok(versions) => {
+                    self.packages.insert(name, versions);
+                },
+                Err(e) => {
+                    self.warnings.push(e);
+                    self.skipped.push(dir);
+                }
+            }
+        }
+    }
+
+    // Directories that weren't loaded, because nothing needed them or because
+    // they aren't packages.
+    pub fn skipped(&self) -> &Vec<PathBuf> {
+        &self.skipped
+    }
+
+    // What was wrong with the packages in skipped directories that were
+    // looked at.
+    pub fn warnings(&self) -> &Vec<Error> {
+        &self.warnings
+    }
+
+    pub fn prefer(&mut self, versions: BTreeMap<String, String>) {
+        self.preferred = versions;
+    }
+
+    pub fn handle_env_conflicts(&mut self, how: EnvConflicts) {
+        self.env_conflicts = how;
+    }
+
+    fn versions(&self, requirement: &Requirement) -> Result<&Vec<Package>> {
+        match self.packages.get(requirement.name) {
+            Some(versions) => Ok(versions),
+            None => {
+                // load it again to report why it's missing
+                try!(Package::versions(&self.basedir, requirement.name));
+                Err(Error::new(requirement.path.clone(), &format!("{} depends on {}, which doesn't exist", requirement.from, requirement.name)))
+            }
+        }
+    }
+
+    fn conflict(&self, name: &str, requirements: &[Requirement], versions: &[Package]) -> Error {
+        let wanted = requirements.iter()
+            .filter(|r| r.name == name)
+            .map(|r| format!("{} ({})", r.version_req, r.from))
+            .collect::<Vec<String>>();
+        let available = versions.iter().map(|p| p.version()).collect::<Vec<&str>>();
+        let path = requirements.iter().filter(|r| r.name == name).last().map(|r| r.path.clone()).unwrap();
+
+        Error::new(path, &format!("no version of {} satisfies {}; available versions are {}",
+                                  name, wanted.join(" and "), available.join(", ")))
+    }
+
+    // Virtual packages, each with the package that will provide it: one the
+    // roles depend on directly if there is one, otherwise the first by name.
+    fn providers<'a>(&'a self, roles: &'a Vec<Role>) -> HashMap<&'a str, &'a str> {
+        let direct = roles.iter()
+            .flat_map(|role| role.dependencies().iter().map(|dep| dep.name.as_str()))
+            .collect::<HashSet<&str>>();
+
+        let mut candidates: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (name, versions) in self.packages.iter() {
+            for virtual_name in versions.iter().flat_map(|p| p.provides.iter()) {
+                // a real package always wins over a virtual one
+                if ! self.packages.contains_key(virtual_name) {
+                    let v = candidates.entry(virtual_name).or_insert_with(Vec::new);
+                    if ! v.contains(&name.as_str()) {
+                        v.push(name);
                     }
-                    v
+                }
+            }
+        }
+
+        candidates.into_iter()
+            .map(|(virtual_name, v)| (virtual_name, v.iter().cloned().find(|p| direct.contains(p)).unwrap_or(v[0])))
+            .collect()
+    }
+
+    // A version picked for `next`, with the ones left to try if it doesn't
+    // work out. `len` is how many requirements there were before its own
+    // were added.
+    fn candidates<'a>(&'a self, first: usize, requirements: &[Requirement<'a>]) -> Result<Choice<'a>> {
+        let next = requirements[first].name;
+        let versions = try!(self.versions(&requirements[first]));
+
+        let mut candidates = versions.iter().collect::<Vec<&Package>>();
+        if let Some(preferred) = self.preferred.get(next) {
+            candidates.sort_by_key(|p| p.version() != preferred);
+        }
+        Ok(Choice{name: next, first: first, candidates: candidates, tried: 0, len: requirements.len(), first_error: None})
+    }
+
+    // Tries the rest of a choice's candidates until one fits with everything
+    // picked so far, and adds its requirements.
+    fn next_candidate<'a>(&'a self, choice: &mut Choice<'a>, requirements: &mut Vec<Requirement<'a>>, selected: &mut HashMap<&'a str, &'a Package>, providers: &HashMap<&'a str, &'a str>, facts: Option<&Facts>) -> bool {
+        while choice.tried < choice.candidates.len() {
+            let candidate = choice.candidates[choice.tried];
+            choice.tried += 1;
+            if ! requirements.iter().filter(|r| r.name == choice.name).all(|r| r.allows(candidate)) {
+                continue;
+            }
+
+            let mut result = Ok(());
+            for name in candidate.dependencies_on(facts) {
+                let version_req = &candidate.dependencies()[name];
+                match Requirement::new(name, version_req, format!("package {} {}", candidate.name, candidate.version()),
+                                       candidate.dir.join("package.toml"), providers) {
+                    Ok(requirement) => requirements.push(requirement),
+                    Err(e) => result = Err(e)
+                }
+            }
+
+            // anything this version asks for that's already been picked has to fit
+            let clash = requirements[choice.len..].iter()
+                .find(|r| selected.get(r.name).map(|p| !r.allows(p)).unwrap_or(false))
+                .map(|r| r.name);
+
+            let error = match (result, clash) {
+                (Err(e), _) => e,
+                (Ok(()), Some(name)) => self.conflict(name, requirements, &self.packages[name]),
+                (Ok(()), None) => {
+                    selected.insert(choice.name, candidate);
+                    return true;
+                }
+            };
+            requirements.truncate(choice.len);
+            choice.fail(error);
+        }
+        false
+    }
+
+    // Pick one version of every package the roles need so that every
+    // requirement on it is met, preferring newer versions. When a choice
+    // leads to a conflict further down, the next newest is tried instead.
+    // The choices made so far are kept in a stack rather than by recursing,
+    // since there's one for every package.
+    fn select<'a>(&'a self, requirements: &mut Vec<Requirement<'a>>, selected: &mut HashMap<&'a str, &'a Package>, providers: &HashMap<&'a str, &'a str>, facts: Option<&Facts>) -> Result<()> {
+        let mut choices: Vec<Choice<'a>> = Vec::new();
+        loop {
+            let start = choices.last().map(|choice| choice.first).unwrap_or(0);
+            let first = match requirements[start..].iter().position(|r| !selected.contains_key(r.name)) {
+                Some(i) => start + i,
+                None => return Ok(())
+            };
+            let mut error = match self.candidates(first, requirements) {
+                Ok(mut choice) => {
+                    if self.next_candidate(&mut choice, requirements, selected, providers, facts) {
+                        choices.push(choice);
+                        continue;
+                    }
+                    choice.error(self, requirements)
+                },
+                Err(e) => e
+            };
+
+            // undo choices until one has another candidate that fits
+            loop {
+                let mut choice = match choices.pop() {
+                    Some(choice) => choice,
+                    None => return Err(error)
                 };
-                deps.insert(package, v);
+                selected.remove(choice.name);
+                requirements.truncate(choice.len);
+                choice.fail(error);
+                if self.next_candidate(&mut choice, requirements, selected, providers, facts) {
+                    choices.push(choice);
+                    break;
+                }
+                error = choice.error(self, requirements);
             }
-        };
+        }
+    }
 
-        Ok(PackageRepository{deps: deps})
+    // Without facts every `when` counts as true, like at compile time.
+    pub fn resolve(&self, roles: &Vec<Role>, facts: Option<&Facts>) -> Result<Vec<Executable>> {
+        let (exes, conflicts) = try!(self.sort(roles, facts));
+        match conflicts.into_iter().next() {
+            Some(ref conflict) if self.env_conflicts == EnvConflicts::Error => {
+                Err(Error::new(conflict.path.clone(), &format!("{}, and env conflicts are errors", conflict)))
+            },
+            _ => Ok(exes)
+        }
     }
 
-    fn dependencies(&self, package: &Package) -> Option<&Vec<Package>> {
-        self.deps.get(package).and_then(|d|
-                                        if d.len() == 0 {
-                                            None
-                                        } else {
-                                            Some(d)
-                                        })
+    // Roles that share a package, but set different values for its env.
+    pub fn env_conflicts(&self, roles: &Vec<Role>, facts: Option<&Facts>) -> Result<Vec<EnvConflict>> {
+        self.sort(roles, facts).map(|(_, conflicts)| conflicts)
     }
 
-    pub fn resolve(&self, roles: &Vec<Role>) -> Result<Vec<Executable>> {
-        let mut marks: HashSet<Package> = HashSet::new();
-        let mut temp_marks = HashSet::new();
+    fn sort(&self, roles: &Vec<Role>, facts: Option<&Facts>) -> Result<(Vec<Executable>, Vec<EnvConflict>)> {
+        let providers = self.providers(roles);
+        let mut requirements = Vec::new();
+        for role in roles {
+            for dep in role.dependencies().iter().filter(|dep| dep.applies(facts)) {
+                requirements.push(try!(Requirement::new(&dep.name, &dep.version_req, format!("role {}", role.name), role.path.clone(), &providers)));
+            }
+        }
+        let mut selected = HashMap::new();
+        try!(self.select(&mut requirements, &mut selected, &providers, facts));
+
+        // from here on a virtual package is just another name for its provider
+        for (virtual_name, provider) in providers.iter() {
+            if let Some(package) = selected.get(provider).cloned() {
+                selected.insert(*virtual_name, package);
+            }
+        }
+
+        // two packages that conflict can't both be used. Without facts, as
+        // when compiling, only the packages every host uses are checked:
+        // conditional ones might never meet, so they're checked on the host.
+        let mut used = match facts {
+            Some(_) => selected.values().cloned().collect::<Vec<&Package>>(),
+            None => {
+                let mut always = Vec::new();
+                let mut todo = roles.iter()
+                    .flat_map(|role| role.dependencies().iter().filter(|dep| dep.when.is_none()))
+                    .map(|dep| selected[dep.name.as_str()])
+                    .collect::<Vec<&Package>>();
+                while let Some(package) = todo.pop() {
+                    if ! always.iter().any(|p: &&Package| p.name == package.name) {
+                        always.push(package);
+                        todo.extend(package.dependencies().keys()
+                                    .filter(|name| package.condition(name).is_none())
+                                    .map(|name| selected[name.as_str()]));
+                    }
+                }
+                always
+            }
+        };
+        used.sort_by(|a, b| a.name.cmp(&b.name));
+        used.dedup_by(|a, b| a.name == b.name);
+        for package in used.iter() {
+            for conflict in package.conflicts.iter() {
+                if let Some(other) = used.iter().find(|p| p.name != package.name && (p.name == *conflict || p.provides.contains(conflict))) {
+                    let what = if other.name == *conflict { String::new() } else { format!(", which provides {},", conflict) };
+                    return Err(Error::new(package.dir.join("package.toml"),
+                                          &format!("package {} conflicts with {}, but {}{} is used too", package.name, conflict, other.name, what)));
+                }
+            }
+        }
+
+        // a role setting a var that none of its packages declare is most
+        // likely a typo, which would otherwise go unnoticed. That's checked
+        // when compiling, since on the host some packages may be left out.
+        for role in roles.iter().filter(|_| facts.is_none()) {
+            let mut reachable = HashSet::new();
+            let mut todo = role.dependencies().iter().map(|dep| dep.name.as_str()).collect::<Vec<&str>>();
+            while let Some(name) = todo.pop() {
+                if reachable.insert(name) {
+                    todo.extend(selected[name].dependencies().keys().map(|name| name.as_str()));
+                }
+            }
+
+            for k in role.env().keys() {
+                if ! reachable.iter().any(|name| selected[name].declares(k)) {
+                    return Err(Error::new(role.path.clone(), &format!("role `env` sets \"{}\", which none of its packages declare.", k)));
+                }
+            }
 
-        let mut sorted = Vec::with_capacity(roles.len());
+            for (name, vars) in role.package_env.iter() {
+                let package = match reachable.get(name.as_str()) {
+                    Some(name) => selected[name],
+                    None => return Err(Error::new(role.path.clone(), &format!("role `env.{}` is for a package the role doesn't use.", name)))
+                };
+                for k in vars.keys() {
+                    if ! package.declares(k) {
+                        return Err(Error::new(role.path.clone(), &format!("role `env.{}` sets \"{}\", which package {} doesn't declare.", name, k, name)));
+                    }
+                }
+            }
+        }
 
+        // the packages each role uses, and the roles that use each package
+        let mut reachable_by_role = Vec::with_capacity(roles.len());
+        let mut users: HashMap<&str, Vec<&Role>> = HashMap::new();
         for role in roles {
+            let mut reachable = BTreeSet::new();
+            let mut todo = role.dependencies().iter()
+                .filter(|dep| dep.applies(facts))
+                .map(|dep| selected[dep.name.as_str()])
+                .collect::<Vec<&Package>>();
+            while let Some(package) = todo.pop() {
+                if reachable.insert(package.name.as_str()) {
+                    users.entry(package.name.as_str()).or_insert_with(Vec::new).push(role);
+                    todo.extend(package.dependencies_on(facts).into_iter().map(|name| selected[name]));
+                }
+            }
+            reachable_by_role.push(reachable);
+        }
+
+        // a package that's shared runs with what all of its roles set, unless
+        // it runs once per role, with just what each one sets
+        let per_role = self.env_conflicts == EnvConflicts::PerRole;
+        let mut conflicts = Vec::new();
+        let mut shared = HashMap::new();
+        for (name, sharing) in users.iter().filter(|&(_, sharing)| sharing.len() > 1) {
+            conflicts.append(&mut env_conflicts_between(sharing, selected[name]));
+            if ! per_role {
+                shared.insert(*name, try!(shared_executable(sharing, selected[name], facts)));
+            }
+        }
+        conflicts.sort_by(|a, b| (&a.package, &a.name).cmp(&(&b.package, &b.name)));
+        // a package that's only there to be ordered against runs with the env
+        // of a role that does use it
+        let executable = |shared: &mut HashMap<&str, Executable>, role: &Role, reachable: &BTreeSet<&str>, package: &Package| {
+            let role = if reachable.contains(package.name.as_str()) { role } else { users[package.name.as_str()][0] };
+            match shared.remove(package.name.as_str()) {
+                Some(exe) => Ok(exe),
+                None => Executable::from_role_and_package(role, package, facts)
+            }
+        };
+
+        // one version of each package is used, so names will do
+        let mut marks: HashSet<&str> = HashSet::new();
+        let mut temp_marks: HashSet<&str> = HashSet::new();
+
+        let mut sorted: Vec<Executable> = Vec::with_capacity(roles.len());
+        // running the same package with the same env twice is pointless
+        let push = |sorted: &mut Vec<Executable>, exe: Executable| {
+            if ! per_role || ! sorted.iter().any(|other| other.name == exe.name && other.env == exe.env) {
+                sorted.push(exe);
+            }
+        };
+
+        // `after` and `before` only order packages that some role uses
+        // anyway, and never pull new ones in
+        let all_used = reachable_by_role.iter().flat_map(|reachable| reachable.iter().cloned()).collect::<BTreeSet<&str>>();
+        let used = |name: &str| selected.get(name).map(|p| all_used.contains(p.name.as_str())).unwrap_or(false);
+
+        // packages that have to come before each one because of their `before`
+        let mut before: HashMap<&str, Vec<&Package>> = HashMap::new();
+        for other in all_used.iter().map(|name| selected[name]) {
+            for name in other.before.iter().filter(|name| used(name)) {
+                before.entry(selected[name.as_str()].name.as_str()).or_insert_with(Vec::new).push(other);
+            }
+        }
+
+        let dependencies = |package: &Package| -> Vec<&Package> {
+            let mut deps = package.dependencies_on(facts).into_iter().map(|name| selected[name]).collect::<Vec<&Package>>();
+            let soft = package.after.iter()
+                .filter(|name| used(name))
+                .map(|name| selected[name.as_str()])
+                .chain(before.get(package.name.as_str()).into_iter().flat_map(|v| v.iter().cloned()));
+            for other in soft {
+                if other.name != package.name && ! deps.iter().any(|p| p.name == other.name) {
+                    deps.push(other);
+                }
+            }
+            deps
+        };
+
+        for (role, reachable) in roles.iter().zip(reachable_by_role.iter()) {
+            if per_role {
+                marks.clear();
+            }
 
             let mut stack = Stack::new();
 
             // reverse `packages` and add each to `stack`
-            let mut packages_reversed = role.dependencies().clone();
+            let mut packages_reversed = role.dependencies().iter()
+                .filter(|dep| dep.applies(facts))
+                .map(|dep| selected[dep.name.as_str()])
+                .collect::<Vec<&Package>>();
             packages_reversed.reverse();
             for package in packages_reversed {
                 stack.push(package);
@@ -164,9 +971,9 @@ impl PackageRepository {
                 // then we know their deps have all been dealt with.
                 loop {
                     if let Some(saved) = stack.pop_saved() {
-                        marks.insert(saved.clone());
-                        temp_marks.remove(&saved);
-                        sorted.push(Executable::from_role_and_package(&role, &saved));
+                        marks.insert(saved.name.as_str());
+                        temp_marks.remove(saved.name.as_str());
+                        push(&mut sorted, try!(executable(&mut shared, role, reachable, saved)));
                     } else {
                         break;
                     }
@@ -176,29 +983,34 @@ impl PackageRepository {
                 if let Some(package) = stack.pop() {
 
                     // if it's temp marked then there's a cycle
-                    if temp_marks.contains(&package) {
-                        return Err(Error::new(PathBuf::from("undefined"), "cycle detected"));
+                    if temp_marks.contains(package.name.as_str()) {
+                        let mut chain = stack.ancestors().into_iter().cloned().collect::<Vec<&Package>>();
+                        let start = chain.iter().position(|p| p.name == package.name).unwrap_or(0);
+                        chain.drain(..start);
+                        chain.push(package);
+                        return Err(cycle_error(role, &chain, facts));
                     }
 
                     // if this package is already in `sorted` then ignore it, otherwise continue
-                    if ! marks.contains(&package) {
-                        temp_marks.insert(package.clone());
+                    if ! marks.contains(package.name.as_str()) {
+                        temp_marks.insert(package.name.as_str());
 
-                        if let Some(deps) = self.dependencies(&package) {
+                        let deps = dependencies(package);
+                        if ! deps.is_empty() {
                             // this package has dependencies.
                             // this means we need to save it to the side,
                             // indent the stack, and push all deps
                             stack.push_saved(package);
                             stack.indent();
                             for dep in deps {
-                                stack.push(dep.clone());
+                                stack.push(dep);
                             }
                         } else {
                             // this package has no dependencies
                             // so we can immediately add it to `sorted`
-                            marks.insert(package.clone());
-                            temp_marks.remove(&package);
-                            sorted.push(Executable::from_role_and_package(&role, &package));
+                            marks.insert(package.name.as_str());
+                            temp_marks.remove(package.name.as_str());
+                            push(&mut sorted, try!(executable(&mut shared, role, reachable, package)));
                         }
                     }
                 } else {
@@ -217,14 +1029,127 @@ impl PackageRepository {
                 }
             }
         }
-        Ok(sorted)
+
+        // and dependencies on one are on the provider
+        for exe in sorted.iter_mut() {
+            exe.dependencies = exe.dependencies.iter().map(|name| selected[name.as_str()].name.clone()).collect();
+        }
+        Ok((sorted, conflicts))
+    }
+
+    // The package of each executable, once even when it runs once per role.
+    fn used(&self, exes: &[Executable]) -> Vec<&Package> {
+        let mut seen = HashSet::new();
+        exes.iter()
+            .filter(|exe| seen.insert(exe.name.as_str()))
+            .map(|exe| self.packages[&exe.name].iter().find(|p| p.version() == exe.version).unwrap())
+            .collect()
+    }
+
+    // Every `when` that resolving on a host with these facts looks at: those
+    // of the roles' dependencies, then those of each package that's used, in
+    // the order they'd run.
+    pub fn conditions(&self, roles: &Vec<Role>, facts: Option<&Facts>) -> Result<Vec<Decision>> {
+        let mut decisions = Vec::new();
+        for role in roles {
+            for dep in role.dependencies() {
+                if let Some(ref when) = dep.when {
+                    decisions.push(Decision{from: format!("role {}", role.name),
+                                            name: dep.name.clone(),
+                                            condition: when.clone(),
+                                            holds: condition::applies(Some(when), facts)});
+                }
+            }
+        }
+
+        for package in self.used(&try!(self.resolve(roles, facts))) {
+            for name in package.dependencies().keys() {
+                if let Some(when) = package.condition(name) {
+                    decisions.push(Decision{from: format!("package {} {}", package.name, package.version()),
+                                            name: name.clone(),
+                                            condition: when.clone(),
+                                            holds: condition::applies(Some(when), facts)});
+                }
+            }
+        }
+        Ok(decisions)
+    }
+
+    // The graph `resolve` sorts, with virtual packages replaced by their
+    // providers.
+    pub fn graph(&self, roles: &Vec<Role>, facts: Option<&Facts>) -> Result<Graph> {
+        let exes = try!(self.resolve(roles, facts));
+        let providers = self.providers(roles);
+
+        let roles = roles.iter()
+            .map(|role| (role.name.clone(),
+                         role.dependencies().iter()
+                             .filter(|dep| dep.applies(facts))
+                             .map(|dep| edge(&providers, &dep.name, dep.when.as_ref()))
+                             .collect()))
+            .collect();
+
+        Ok(Graph::new(roles, self.nodes(self.used(&exes), &providers, facts)))
+    }
+
+    // Every package that's loaded, whether a role uses it or not, in the
+    // version compile would try first and with all of its dependencies.
+    // Nothing is resolved, so this works even when the roles wouldn't, and
+    // packages in a dependency cycle come in no particular order.
+    pub fn repository_graph(&self) -> Graph {
+        let no_roles = vec![];
+        let providers = self.providers(&no_roles);
+        let chosen = self.packages.iter()
+            .map(|(name, versions)| {
+                let preferred = self.preferred.get(name);
+                (name.as_str(), versions.iter().find(|p| preferred.map(|v| p.version() == v).unwrap_or(false)).unwrap_or(&versions[0]))
+            })
+            .collect::<BTreeMap<&str, &Package>>();
+        let dependency = |name: &str| {
+            let name = providers.get(name).cloned().unwrap_or(name);
+            chosen.get(name).cloned()
+        };
+
+        // depth first, putting each package after what it depends on
+        let mut order = Vec::with_capacity(chosen.len());
+        let mut seen = HashSet::new();
+        for &package in chosen.values() {
+            if ! seen.insert(package.name.as_str()) {
+                continue;
+            }
+            let mut stack = vec![(package, package.dependencies_on(None).into_iter())];
+            while ! stack.is_empty() {
+                let next = stack.last_mut().unwrap().1.next();
+                match next {
+                    Some(name) => if let Some(dep) = dependency(name) {
+                        if seen.insert(dep.name.as_str()) {
+                            stack.push((dep, dep.dependencies_on(None).into_iter()));
+                        }
+                    },
+                    None => order.push(stack.pop().unwrap().0)
+                }
+            }
+        }
+
+        Graph::new(vec![], self.nodes(order, &providers, None))
+    }
+
+    fn nodes(&self, packages: Vec<&Package>, providers: &HashMap<&str, &str>, facts: Option<&Facts>) -> Vec<Node> {
+        packages.into_iter()
+            .map(|package| Node{name: package.name.clone(),
+                                version: package.version().to_string(),
+                                dependencies: package.dependencies_on(facts).into_iter().map(|name| edge(providers, name, package.condition(name))).collect()})
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Stack, PackageRepository};
-    use runlist::{Env, Package, Role};
+    use super::{Edge, EnvConflicts, Executable, Stack, PackageRepository};
+    use condition::Condition;
+    use facts::Facts;
+    use runlist::{Dependency, Env, Package, Role};
+    use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
 
     fn resource<P: AsRef<Path>>(path: P) -> PathBuf {
@@ -234,8 +1159,27 @@ mod tests {
     fn quick_role<P: AsRef<Path>>(path: P, name: String, dependencies: Vec<Package>, env: Env) -> Role {
         Role { path: path.as_ref().to_path_buf(),
                name: name,
-               dependencies: dependencies,
-               env: env }
+               includes: vec![],
+               dependencies: dependencies.iter().map(|p| Dependency{name: p.name.clone(), version_req: "*".to_string(), when: None}).collect(),
+               env: env,
+               package_env: BTreeMap::new() }
+    }
+
+    fn versioned_role<P: AsRef<Path>>(path: P, dependencies: Vec<(&str, &str)>) -> Role {
+        Role { path: path.as_ref().join("roles").join("foo.toml"),
+               name: "foo".to_string(),
+               includes: vec![],
+               dependencies: dependencies.iter().map(|&(n, v)| Dependency{name: n.to_string(), version_req: v.to_string(), when: None}).collect(),
+               env: Env::new(),
+               package_env: BTreeMap::new() }
+    }
+
+    fn names_and_versions(repo: &PackageRepository, role: Role) -> Vec<(String, String)> {
+        repo.resolve(&vec![role], None).unwrap().into_iter().map(|exe| (exe.name, exe.version)).collect()
+    }
+
+    fn pairs(v: Vec<(&str, &str)>) -> Vec<(String, String)> {
+        v.into_iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
     }
 
     #[test]
@@ -315,15 +1259,15 @@ mod tests {
         let z = Package::from_file(&basedir, "z").unwrap();
 
         let role = quick_role(&basedir, "foo".to_string(), vec![a.clone()], Env::new());
-        assert_eq!(repo.resolve(&vec![role]).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
+        assert_eq!(repo.resolve(&vec![role], None).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
                    vec!["d", "c", "b", "a"]);
 
         let role = quick_role(&basedir, "foo".to_string(), vec![a.clone(), z.clone()], Env::new());
-        assert_eq!(repo.resolve(&vec![role]).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
+        assert_eq!(repo.resolve(&vec![role], None).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
                    vec!["d", "c", "b", "a", "z"]);
 
         let role = quick_role(&basedir, "foo".to_string(), vec![a.clone(), c.clone()], Env::new());
-        assert_eq!(repo.resolve(&vec![role]).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
+        assert_eq!(repo.resolve(&vec![role], None).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
                    vec!["d", "c", "b", "a"]);
     }
 
@@ -335,8 +1279,12 @@ mod tests {
         let a = Package::from_file(&basedir, "a").unwrap();
 
         let role = quick_role(&basedir, "foo".to_string(), vec![a.clone()], Env::new());
-        let r = repo.resolve(&vec![role]);
-        assert!(r.is_err());
+        let err = repo.resolve(&vec![role], None).err().unwrap();
+        assert_eq!(err.path(), basedir);
+        assert_eq!(err.message(), format!("dependency cycle in role foo: a -> b -> c -> a\n  a: {}\n  b: {}\n  c: {}",
+                                          basedir.join("a").join("package.toml").display(),
+                                          basedir.join("b").join("package.toml").display(),
+                                          basedir.join("c").join("package.toml").display()));
     }
 
     #[test]
@@ -347,10 +1295,240 @@ mod tests {
         let a = Package::from_file(&basedir, "a").unwrap();
 
         let role = quick_role(&basedir, "foo".to_string(), vec![a.clone()], Env::new());
-        assert_eq!(repo.resolve(&vec![role]).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
+        assert_eq!(repo.resolve(&vec![role], None).unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
                    vec!["d", "c", "b", "c1", "a"]);
     }
 
+    #[test]
+    fn conditional_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("conditional");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let app = Package::from_file(&basedir, "app").unwrap();
+        let names = |exes: Vec<Executable>| exes.into_iter().map(|exe| (exe.name, exe.dependencies)).collect::<Vec<(String, Vec<String>)>>();
+        let role = || quick_role(&basedir, "foo".to_string(), vec![app.clone()], Env::new());
+
+        // without facts, as when compiling, everything that could be needed is
+        assert_eq!(names(repo.resolve(&vec![role()], None).unwrap()),
+                   vec![("apt".to_string(), vec![]), ("apk".to_string(), vec![]),
+                        ("app".to_string(), vec!["apk".to_string(), "apt".to_string()])]);
+
+        let debian = Facts{os: "ubuntu".to_string(), os_family: "debian".to_string(), ..Facts::default()};
+        assert_eq!(names(repo.resolve(&vec![role()], Some(&debian)).unwrap()),
+                   vec![("apt".to_string(), vec![]), ("app".to_string(), vec!["apt".to_string()])]);
+
+        let decisions = repo.conditions(&vec![role()], Some(&debian)).unwrap();
+        assert_eq!(decisions.iter().map(|d| (d.from.as_str(), d.name.as_str(), d.condition.to_string(), d.holds)).collect::<Vec<_>>(),
+                   vec![("package app 1.0.0", "apk", "os_family == \"alpine\"".to_string(), false),
+                        ("package app 1.0.0", "apt", "os_family == \"debian\"".to_string(), true)]);
+
+        // a role's dependencies can have conditions too
+        let mut role = role();
+        role.dependencies[0].when = Some(Condition::parse("os == \"debian\"").unwrap());
+        assert!(repo.resolve(&vec![role], Some(&debian)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn soft_ordering_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("ordering");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let names = |deps: Vec<(&str, &str)>| repo.resolve(&vec![versioned_role(&basedir, deps)], None).unwrap()
+            .into_iter().map(|exe| exe.name).collect::<Vec<String>>();
+
+        // app runs after db, but doesn't need it
+        assert_eq!(names(vec![("app", "*")]), vec!["app"]);
+        assert_eq!(names(vec![("app", "*"), ("db", "*")]), vec!["db", "app"]);
+
+        // monitor runs before app
+        assert_eq!(names(vec![("app", "*"), ("monitor", "*")]), vec!["monitor", "app"]);
+        assert_eq!(names(vec![("monitor", "*"), ("app", "*"), ("db", "*")]), vec!["monitor", "db", "app"]);
+
+        // db can come from another role, too
+        let roles = vec![versioned_role(&basedir, vec![("app", "*")]), versioned_role(&basedir, vec![("db", "*")])];
+        let exes = repo.resolve(&roles, None).unwrap();
+        assert_eq!(exes.into_iter().map(|exe| exe.name).collect::<Vec<String>>(), vec!["db", "app"]);
+
+        // but the order can't go round in a circle
+        let err = repo.resolve(&vec![versioned_role(&basedir, vec![("loop_a", "*"), ("loop_b", "*")])], None).err().unwrap();
+        assert!(err.message().starts_with("dependency cycle in role foo: loop_a -> loop_b (after) -> loop_a (after)\n"));
+        assert_eq!(names(vec![("loop_a", "*")]), vec!["loop_a"]);
+    }
+
+    #[test]
+    fn virtual_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("virtual");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let names = |role: Role| repo.resolve(&vec![role], None).unwrap().into_iter().map(|exe| (exe.name, exe.dependencies)).collect::<Vec<(String, Vec<String>)>>();
+
+        // the first provider by name, unless the role picks one itself
+        assert_eq!(names(versioned_role(&basedir, vec![("app", "*")])),
+                   vec![("rsyslog".to_string(), vec![]), ("app".to_string(), vec!["rsyslog".to_string()])]);
+        assert_eq!(names(versioned_role(&basedir, vec![("syslog-ng", "*"), ("app", "*")])),
+                   vec![("syslog-ng".to_string(), vec![]), ("app".to_string(), vec!["syslog-ng".to_string()])]);
+        assert_eq!(names(versioned_role(&basedir, vec![("logging-agent", "*")])),
+                   vec![("rsyslog".to_string(), vec![])]);
+
+        let err = repo.resolve(&vec![versioned_role(&basedir, vec![("rsyslog", "*"), ("syslog-ng", "*")])], None).err().unwrap();
+        assert_eq!(err.path(), basedir.join("rsyslog").join("package.toml"));
+        assert_eq!(err.message(), "package rsyslog conflicts with logging-agent, but syslog-ng, which provides logging-agent, is used too");
+
+        let err = repo.resolve(&vec![versioned_role(&basedir, vec![("logging-agent", ">=1")])], None).err().unwrap();
+        assert_eq!(err.message(), "role foo depends on logging-agent \">=1\", but logging-agent is a virtual package, so it has no versions");
+    }
+
+    #[test]
+    fn conditional_conflicts_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("conditional_conflicts");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let names = |exes: Vec<Executable>| exes.into_iter().map(|exe| exe.name).collect::<Vec<String>>();
+        let debian = Facts{os: "debian".to_string(), os_family: "debian".to_string(), ..Facts::default()};
+
+        // no host gets both rsyslog and syslog-ng, so they compile together
+        let role = versioned_role(&basedir, vec![("app", "*")]);
+        assert_eq!(names(repo.resolve(&vec![role], None).unwrap()), vec!["syslog-ng", "rsyslog", "app"]);
+        let role = versioned_role(&basedir, vec![("app", "*")]);
+        assert_eq!(names(repo.resolve(&vec![role], Some(&debian)).unwrap()), vec!["rsyslog", "app"]);
+
+        // but on a host where both are used, they still conflict
+        let role = versioned_role(&basedir, vec![("app", "*"), ("syslog-ng", "*")]);
+        assert!(repo.resolve(&vec![role], None).is_ok());
+        let role = versioned_role(&basedir, vec![("app", "*"), ("syslog-ng", "*")]);
+        let err = repo.resolve(&vec![role], Some(&debian)).err().unwrap();
+        assert_eq!(err.message(), "package rsyslog conflicts with logging-agent, but syslog-ng, which provides logging-agent, is used too");
+    }
+
+    #[test]
+    fn lazy_repository_test() {
+        let basedir = resource("package_repository_unit_tests").join("lazy").canonicalize().unwrap();
+        let role = || versioned_role(&basedir, vec![("app", "*")]);
+
+        // only app and db are loaded, so broken doesn't get in the way
+        let repo = PackageRepository::for_roles(&basedir, &vec![role()]).unwrap();
+        assert_eq!(repo.packages.keys().collect::<Vec<&String>>(), vec!["app", "db"]);
+        assert_eq!(repo.skipped(), &vec![basedir.join("broken"), basedir.join("unrelated")]);
+        assert!(repo.warnings().is_empty());
+        assert_eq!(names_and_versions(&repo, role()), pairs(vec![("db", "1.0.0"), ("app", "1.0.0")]));
+
+        // loading everything turns it into a warning
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        assert_eq!(repo.skipped(), &vec![basedir.join("broken")]);
+        assert_eq!(repo.warnings().iter().map(|e| e.to_string()).collect::<Vec<String>>(),
+                   vec![format!("{}: package `name` doesn't match its directory.", basedir.join("broken").join("package.toml").display())]);
+        assert_eq!(names_and_versions(&repo, role()), pairs(vec![("db", "1.0.0"), ("app", "1.0.0")]));
+
+        // unless something depends on it
+        let broken = || versioned_role(&basedir, vec![("broken", "*")]);
+        assert_eq!(PackageRepository::for_roles(&basedir, &vec![broken()]).err().unwrap().message(), "package `name` doesn't match its directory.");
+        assert_eq!(repo.resolve(&vec![broken()], None).err().unwrap().message(), "package `name` doesn't match its directory.");
+
+        // providers of virtual packages could be anywhere
+        let basedir = resource("package_repository_unit_tests").join("virtual");
+        let repo = PackageRepository::for_roles(&basedir, &vec![versioned_role(&basedir, vec![("logging-agent", "*")])]).unwrap();
+        assert!(repo.skipped().is_empty());
+        assert_eq!(names_and_versions(&repo, versioned_role(&basedir, vec![("logging-agent", "*")])), pairs(vec![("rsyslog", "1.0.0")]));
+    }
+
+    #[test]
+    fn graph_test() {
+        let basedir = resource("package_repository_unit_tests").join("virtual");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let edge = |name: &str| Edge{name: name.to_string(), condition: None};
+
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("app", "*")])], None).unwrap();
+        assert_eq!(graph.roles, vec![("foo".to_string(), vec![edge("app")])]);
+        assert_eq!(graph.packages.iter().map(|node| (node.name.as_str(), node.version.as_str())).collect::<Vec<_>>(),
+                   vec![("rsyslog", "1.0.0"), ("app", "1.0.0")]);
+        assert_eq!(graph.packages[1].dependencies, vec![edge("rsyslog")]);
+
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("logging-agent", "*")])], None).unwrap();
+        assert_eq!(graph.roles[0].1, vec![edge("rsyslog")]);
+
+        let basedir = resource("package_repository_unit_tests").join("more_complicated");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("a", "*")])], None).unwrap();
+        let paths = |name: &str, limit: usize| graph.paths(name, limit).into_iter()
+            .map(|(role, path)| format!("{}: {}", role, path.iter().map(|e| e.name.as_str()).collect::<Vec<&str>>().join(" -> ")))
+            .collect::<Vec<String>>();
+        assert_eq!(paths("a", 10), vec!["foo: a"]);
+        assert_eq!(paths("c", 10), vec!["foo: a -> b -> c", "foo: a -> c1 -> b -> c"]);
+        assert_eq!(paths("c", 1), vec!["foo: a -> b -> c"]);
+        assert!(paths("nope", 10).is_empty());
+
+        let basedir = resource("package_repository_unit_tests").join("conditional");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("app", "*")])], None).unwrap();
+        assert_eq!(graph.packages[2].dependencies.iter().map(|e| (e.name.as_str(), e.condition.as_ref().unwrap().to_string())).collect::<Vec<_>>(),
+                   vec![("apk", "os_family == \"alpine\"".to_string()), ("apt", "os_family == \"debian\"".to_string())]);
+    }
+
+    #[test]
+    fn graph_queries_test() {
+        let basedir = resource("package_repository_unit_tests").join("more_complicated");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("a", "*")])], None).unwrap();
+
+        assert_eq!(graph.package("b").map(|node| node.version.as_str()), Some("17"));
+        assert!(graph.package("nope").is_none());
+        assert_eq!(graph.order(), vec!["d", "c", "b", "c1", "a"]);
+        assert_eq!(graph.dependencies("a"), vec!["b", "c1"]);
+        assert_eq!(graph.dependencies("d"), Vec::<&str>::new());
+        assert_eq!(graph.dependents("b"), vec!["c1", "a"]);
+        assert_eq!(graph.dependents("a"), Vec::<&str>::new());
+        assert_eq!(graph.closure("c1"), vec!["d", "c", "b"]);
+        assert_eq!(graph.closure("a"), vec!["d", "c", "b", "c1"]);
+
+        // the role only depends on a, which isn't in there
+        let subgraph = graph.subgraph(&["c1"]);
+        assert_eq!(subgraph.order(), vec!["d", "c", "b", "c1"]);
+        assert!(subgraph.roles.is_empty());
+        assert_eq!(graph.subgraph(&["a"]), graph);
+
+        // edges are on providers, and keep their conditions
+        let basedir = resource("package_repository_unit_tests").join("virtual");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("app", "*")])], None).unwrap();
+        assert_eq!(graph.dependents("rsyslog"), vec!["app"]);
+        assert!(graph.package("logging-agent").is_none());
+
+        let basedir = resource("package_repository_unit_tests").join("conditional");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let debian = Facts{os_family: "debian".to_string(), ..Facts::default()};
+        let graph = repo.graph(&vec![versioned_role(&basedir, vec![("app", "*")])], Some(&debian)).unwrap();
+        assert_eq!(graph.closure("app"), vec!["apt"]);
+        assert_eq!(graph.subgraph(&["apt"]).package("apt").unwrap().dependencies, vec![]);
+    }
+
+    #[test]
+    fn repository_graph_test() {
+        let basedir = resource("package_repository_unit_tests").join("more_complicated");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.repository_graph();
+        assert!(graph.roles.is_empty());
+        assert_eq!(graph.order(), vec!["c", "d", "b", "c1", "a"]);
+        assert_eq!(graph.dependents("b"), vec!["c1", "a"]);
+
+        // packages no role uses are in there too, and conditions all count
+        let basedir = resource("package_repository_unit_tests").join("conditional");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.repository_graph();
+        assert_eq!(graph.closure("app"), vec!["apk", "apt"]);
+
+        // edges are on providers
+        let basedir = resource("package_repository_unit_tests").join("virtual");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let graph = repo.repository_graph();
+        assert_eq!(graph.dependencies("app"), vec!["rsyslog"]);
+        assert!(graph.package("logging-agent").is_none());
+
+        // the version the lockfile prefers, rather than the newest
+        let basedir = resource("package_repository_unit_tests").join("versions");
+        let mut repo = PackageRepository::from_basedir(&basedir).unwrap();
+        assert_eq!(repo.repository_graph().package("nginx").unwrap().version, "2.1.0");
+        let mut preferred = BTreeMap::new();
+        preferred.insert("nginx".to_string(), "1.2.0".to_string());
+        repo.prefer(preferred);
+        assert_eq!(repo.repository_graph().package("nginx").unwrap().version, "1.2.0");
+    }
+
     #[test]
     fn env_test() {
         let basedir = resource("package_repository_unit_tests").join("env");
@@ -365,7 +1543,7 @@ mod tests {
         let mut expected_env = Env::new();
         expected_env.insert("FOO".to_string(), "foo from package".to_string());
         expected_env.insert("BAR".to_string(), "bar from package".to_string());
-        let exes = repo.resolve(&vec![role]).unwrap();
+        let exes = repo.resolve(&vec![role], None).unwrap();
         assert_eq!(exes.len(), 1);
         assert_eq!(exes[0].env, expected_env);
 
@@ -376,7 +1554,7 @@ mod tests {
         let mut expected_env = Env::new();
         expected_env.insert("FOO".to_string(), "foo from role".to_string());
         expected_env.insert("BAR".to_string(), "bar from package".to_string());
-        let exes = repo.resolve(&vec![role]).unwrap();
+        let exes = repo.resolve(&vec![role], None).unwrap();
         assert_eq!(exes.len(), 1);
         assert_eq!(exes[0].env, expected_env);
 
@@ -384,19 +1562,246 @@ mod tests {
         let role_env = Env::new();
         let role = quick_role(&basedir, "foo".to_string(), vec![no_env.clone()], role_env);
         let expected_env = Env::new();
-        let exes = repo.resolve(&vec![role]).unwrap();
+        let exes = repo.resolve(&vec![role], None).unwrap();
         assert_eq!(exes.len(), 1);
         assert_eq!(exes[0].env, expected_env);
 
-        // role override for a package with no env
+        // role override for a package with no env is an error, it's probably a typo
         let mut role_env = Env::new();
         role_env.insert("FOO".to_string(), "foo from role".to_string());
         let role = quick_role(&basedir, "foo".to_string(), vec![no_env.clone()], role_env);
-        let expected_env = Env::new();
-        let exes = repo.resolve(&vec![role]).unwrap();
+        let err = repo.resolve(&vec![role], None).err().unwrap();
+        assert_eq!(err.path(), basedir.as_path());
+        assert_eq!(err.message(), "role `env` sets \"FOO\", which none of its packages declare.");
+    }
+
+    #[test]
+    fn env_conflicts_test() {
+        let basedir = resource("package_repository_unit_tests").join("env");
+        let mut repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let has_env = Package::from_file(&basedir, "has_env").unwrap();
+        let role = |name: &str, vars: Vec<(&str, &str)>| {
+            let env = vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Env>();
+            quick_role(basedir.join("roles").join(format!("{}.toml", name)), name.to_string(), vec![has_env.clone()], env)
+        };
+        let roles = || vec![role("first", vec![("FOO", "foo from first")]),
+                            role("second", vec![("FOO", "foo from second"), ("BAR", "bar from second")])];
+        let envs = |repo: &PackageRepository| repo.resolve(&roles(), None).unwrap().into_iter()
+            .map(|exe| (exe.env["FOO"].clone(), exe.env["BAR"].clone()))
+            .collect::<Vec<(String, String)>>();
+
+        // the first role that sets something wins, but what only the second
+        // one sets isn't lost
+        assert_eq!(envs(&repo), vec![("foo from first".to_string(), "bar from second".to_string())]);
+        let conflicts = repo.env_conflicts(&roles(), None).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].to_string(), "env \"FOO\" of package has_env is \"foo from first\" in role first but \"foo from second\" in role second");
+        assert_eq!(conflicts[0].path, basedir.join("roles").join("second.toml"));
+
+        repo.handle_env_conflicts(EnvConflicts::PerRole);
+        assert_eq!(envs(&repo), vec![("foo from first".to_string(), "bar from package".to_string()),
+                                     ("foo from second".to_string(), "bar from second".to_string())]);
+        // but only when it makes a difference
+        assert_eq!(repo.resolve(&vec![role("first", vec![]), role("second", vec![])], None).unwrap().len(), 1);
+
+        repo.handle_env_conflicts(EnvConflicts::Error);
+        let err = repo.resolve(&roles(), None).err().unwrap();
+        assert_eq!(err.path(), basedir.join("roles").join("second.toml"));
+        assert!(err.message().ends_with("in role second, and env conflicts are errors"));
+        assert!(repo.resolve(&vec![role("first", vec![("FOO", "same")]), role("second", vec![("FOO", "same")])], None).is_ok());
+
+        // what refers to a merged var sees the value that won
+        repo.handle_env_conflicts(EnvConflicts::Merge);
+        let interpolated = Package::from_file(&basedir, "interpolated").unwrap();
+        let interpolated_role = |name: &str, vars: Vec<(&str, &str)>| {
+            let env = vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Env>();
+            quick_role(basedir.join("roles").join(format!("{}.toml", name)), name.to_string(), vec![interpolated.clone()], env)
+        };
+        let exes = repo.resolve(&vec![interpolated_role("first", vec![]), interpolated_role("second", vec![("PORT", "9000")])], None).unwrap();
         assert_eq!(exes.len(), 1);
-        assert_eq!(exes[0].env, expected_env);
+        assert_eq!(exes[0].env["PORT"], "9000");
+        assert_eq!(exes[0].env["URL"], "http://interpolated.internal:9000/first/");
+
+        // per role, only each role's own env has to expand
+        let cyclic = vec![interpolated_role("first", vec![("PORT", "${HOST}")]), interpolated_role("second", vec![("HOST", "${PORT}")])];
+        assert!(repo.resolve(&cyclic, None).is_err());
+        repo.handle_env_conflicts(EnvConflicts::PerRole);
+        let exes = repo.resolve(&cyclic, None).unwrap();
+        assert_eq!(exes.len(), 2);
+        assert_eq!(exes[0].env["PORT"], "interpolated.internal");
+        assert_eq!(exes[1].env["HOST"], "80");
+    }
+
+    #[test]
+    fn env_schema_test() {
+        let basedir = resource("package_repository_unit_tests").join("env");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let has_schema = Package::from_file(&basedir, "has_schema").unwrap();
+        let has_env = Package::from_file(&basedir, "has_env").unwrap();
+
+        let role_with = |vars: Vec<(&str, &str)>| {
+            let env = vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Env>();
+            quick_role(&basedir, "foo".to_string(), vec![has_schema.clone(), has_env.clone()], env)
+        };
+
+        // defaults fill in whatever the role doesn't set
+        let exes = repo.resolve(&vec![role_with(vec![("DOMAIN", "example.com")])], None).unwrap();
+        assert_eq!(exes[0].env["DOMAIN"], "example.com");
+        assert_eq!(exes[0].env["PORT"], "80");
+        assert_eq!(exes[0].env["MODE"], "dev");
+        assert!(!exes[0].env.contains_key("OPTIONAL"));
+
+        // a var from any package in the role can be set
+        let exes = repo.resolve(&vec![role_with(vec![("DOMAIN", "example.com"), ("FOO", "foo from role")])], None).unwrap();
+        assert_eq!(exes[1].env["FOO"], "foo from role");
+
+        let err = repo.resolve(&vec![role_with(vec![])], None).err().unwrap();
+        assert_eq!(err.path(), basedir.as_path());
+        assert_eq!(err.message(), "package has_schema requires env \"DOMAIN\" (the site's domain name), which role foo doesn't set.");
+
+        let err = repo.resolve(&vec![role_with(vec![("DOMAIN", "example.com"), ("PORT", "eighty")])], None).err().unwrap();
+        assert_eq!(err.message(), "env \"PORT\" of package has_schema is \"eighty\", which isn't an integer.");
+
+        let err = repo.resolve(&vec![role_with(vec![("DOMAIN", "example.com"), ("MODE", "staging")])], None).err().unwrap();
+        assert_eq!(err.message(), "env \"MODE\" of package has_schema is \"staging\", which isn't one of \"dev\", \"prod\".");
+
+        let err = repo.resolve(&vec![role_with(vec![("DOMAIN", "Example.com")])], None).err().unwrap();
+        assert_eq!(err.message(), "env \"DOMAIN\" of package has_schema is \"Example.com\", which doesn't match /[a-z0-9.-]+/.");
+
+        let err = repo.resolve(&vec![role_with(vec![("DOMAIN", "example.com"), ("PROT", "8080")])], None).err().unwrap();
+        assert_eq!(err.message(), "role `env` sets \"PROT\", which none of its packages declare.");
+    }
+
+    #[test]
+    fn env_interpolation_test() {
+        let basedir = resource("package_repository_unit_tests").join("env");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let interpolated = Package::from_file(&basedir, "interpolated").unwrap();
+        let has_env = Package::from_file(&basedir, "has_env").unwrap();
+
+        let role_with = |vars: Vec<(&str, &str)>| {
+            let env = vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Env>();
+            quick_role(&basedir, "foo".to_string(), vec![interpolated.clone(), has_env.clone()], env)
+        };
+
+        let exes = repo.resolve(&vec![role_with(vec![])], None).unwrap();
+        assert_eq!(exes[0].env["URL"], "http://interpolated.internal:80/foo/");
+
+        // role values can refer to the rest of the role's env too
+        let exes = repo.resolve(&vec![role_with(vec![("HOST", "${FOO}.example.com"), ("FOO", "www")])], None).unwrap();
+        assert_eq!(exes[0].env["URL"], "http://www.example.com:80/foo/");
+        assert_eq!(exes[1].env["FOO"], "www");
+
+        let err = repo.resolve(&vec![role_with(vec![("PORT", "8${PORT}")])], None).err().unwrap();
+        assert_eq!(err.path(), basedir.as_path());
+        assert_eq!(err.message(), "env \"PORT\" of package interpolated refers to itself through PORT -> PORT");
+
+        let err = repo.resolve(&vec![role_with(vec![("HOST", "${NOPE}")])], None).err().unwrap();
+        assert_eq!(err.message(), "env \"HOST\" of package interpolated refers to \"NOPE\", which isn't defined.");
+    }
+
+    #[test]
+    fn package_env_test() {
+        let basedir = resource("package_repository_unit_tests").join("env");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+        let interpolated = Package::from_file(&basedir, "interpolated").unwrap();
+        let has_schema = Package::from_file(&basedir, "has_schema").unwrap();
+
+        let role_with = |scoped: Vec<(&str, &str, &str)>| {
+            let mut env = Env::new();
+            env.insert("DOMAIN".to_string(), "example.com".to_string());
+            env.insert("PORT".to_string(), "8000".to_string());
+            let mut role = quick_role(&basedir, "foo".to_string(), vec![interpolated.clone(), has_schema.clone()], env);
+            for (package, k, v) in scoped {
+                role.package_env.entry(package.to_string()).or_insert_with(Env::new).insert(k.to_string(), v.to_string());
+            }
+            role
+        };
+
+        // both packages use PORT, but only interpolated gets the scoped value
+        let exes = repo.resolve(&vec![role_with(vec![("interpolated", "PORT", "9000")])], None).unwrap();
+        assert_eq!(exes[0].name, "interpolated");
+        assert_eq!(exes[0].env["PORT"], "9000");
+        assert_eq!(exes[0].env["URL"], "http://interpolated.internal:9000/foo/");
+        assert_eq!(exes[1].name, "has_schema");
+        assert_eq!(exes[1].env["PORT"], "8000");
+
+        // scoped values are validated like any other
+        let err = repo.resolve(&vec![role_with(vec![("has_schema", "PORT", "ninety")])], None).err().unwrap();
+        assert_eq!(err.path(), basedir.as_path());
+        assert_eq!(err.message(), "env \"PORT\" of package has_schema is \"ninety\", which isn't an integer.");
+
+        let err = repo.resolve(&vec![role_with(vec![("interpolated", "DOMAIN", "example.org")])], None).err().unwrap();
+        assert_eq!(err.message(), "role `env.interpolated` sets \"DOMAIN\", which package interpolated doesn't declare.");
+
+        let err = repo.resolve(&vec![role_with(vec![("nginx", "PORT", "80")])], None).err().unwrap();
+        assert_eq!(err.message(), "role `env.nginx` is for a package the role doesn't use.");
+    }
+
+    #[test]
+    fn versions_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("versions");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+
+        // the newest version that fits
+        let role = versioned_role(&basedir, vec![("app", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "1.4.0"), ("app", "1.0.0")]));
+
+        let role = versioned_role(&basedir, vec![("legacy", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "2.1.0"), ("legacy", "17")]));
+
+        // one version that works for everybody
+        let role = versioned_role(&basedir, vec![("legacy", "*"), ("app", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "1.4.0"), ("legacy", "17"), ("app", "1.0.0")]));
+
+        // roles can ask for versions too
+        let role = versioned_role(&basedir, vec![("nginx", "=1.2.0"), ("legacy", "17")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "1.2.0"), ("legacy", "17")]));
+
+        // and every one of them has to hold, like when included roles disagree
+        let role = versioned_role(&basedir, vec![("nginx", ">=1.2.0"), ("nginx", "<2")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "1.4.0")]));
+
+        // lib 2.0.0 needs a util that doesn't exist, so fall back to lib 1.0.0
+        let role = versioned_role(&basedir, vec![("lib", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("util", "1.0.0"), ("lib", "1.0.0")]));
+    }
+
+    #[test]
+    fn version_conflict_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("versions");
+        let repo = PackageRepository::from_basedir(&basedir).unwrap();
+
+        let role = versioned_role(&basedir, vec![("app", "*"), ("modern", "*")]);
+        let err = repo.resolve(&vec![role], None).err().unwrap();
+        assert_eq!(err.path(), basedir.join("modern").join("package.toml"));
+        assert_eq!(err.message(), "no version of nginx satisfies >=1.2, <2 (package app 1.0.0) and ^2 (package modern 3.0.0); available versions are 2.1.0, 1.4.0, 1.2.0");
+
+        let role = versioned_role(&basedir, vec![("nginx", "^3")]);
+        let err = repo.resolve(&vec![role], None).err().unwrap();
+        assert_eq!(err.path(), basedir.join("roles").join("foo.toml"));
+        assert_eq!(err.message(), "no version of nginx satisfies ^3 (role foo); available versions are 2.1.0, 1.4.0, 1.2.0");
+
+        let role = versioned_role(&basedir, vec![("missing", "*")]);
+        let err = repo.resolve(&vec![role], None).err().unwrap();
+        assert_eq!(err.path(), basedir.join("missing").join("package.toml"));
+        assert_eq!(err.message(), "failure to open");
+    }
+
+    #[test]
+    fn preferred_versions_resolver_test() {
+        let basedir = resource("package_repository_unit_tests").join("versions");
+        let mut repo = PackageRepository::from_basedir(&basedir).unwrap();
+
+        let mut preferred = BTreeMap::new();
+        preferred.insert("nginx".to_string(), "1.2.0".to_string());
+        repo.prefer(preferred);
 
+        let role = versioned_role(&basedir, vec![("app", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "1.2.0"), ("app", "1.0.0")]));
 
+        // unless it doesn't fit anymore
+        let role = versioned_role(&basedir, vec![("modern", "*")]);
+        assert_eq!(names_and_versions(&repo, role), pairs(vec![("nginx", "2.1.0"), ("modern", "3.0.0")]));
     }
 }
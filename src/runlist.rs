@@ -1,9 +1,10 @@
 use std::collections::BTreeMap;
 use std::ffi::CString;
 use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::result;
 
 use libc;
@@ -11,9 +12,16 @@ use libc;
 use rustc_serialize::{Encoder, Encodable};
 use toml;
 
+use condition;
+use condition::Condition;
 use error::{Error, Result};
+use facts::Facts;
+use metadata;
+use metadata::Metadata;
+use schema;
+use version;
 
-use resolve::{Executable, PackageRepository};
+use resolve::{Decision, EnvConflict, EnvConflicts, Executable, Graph, PackageRepository};
 
 // Env has to be a BTreeMap instead of regular HashMap because it has
 // to impliment the Hash trait so that Package can derive Hash.
@@ -26,16 +34,53 @@ pub struct Package {
     pub name: String,
     version: String,
     pub main: PathBuf,
+    // exits 0 when `main` has nothing to do
+    pub check: Option<PathBuf>,
+    // other entrypoints, like `uninstall`, for `tsh run --action`
+    pub actions: BTreeMap<String, PathBuf>,
+    // template => the file it's rendered to, both inside `dir`
+    pub templates: BTreeMap<PathBuf, PathBuf>,
     pub env: Env,
-    dependencies: BTreeMap<String, String>
+    // vars declared with a table instead of just a default
+    pub schema: BTreeMap<String, schema::Var>,
+    // virtual packages this can stand in for, like "logging-agent"
+    pub provides: Vec<String>,
+    // packages or virtual packages this can't share a runlist with
+    pub conflicts: Vec<String>,
+    // packages to run before or after this one, when they're used anyway
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+    dependencies: BTreeMap<String, String>,
+    // `when` of the dependencies that have one
+    conditions: BTreeMap<String, Condition>,
+}
+
+// A package that a role or another package needs, and which versions of it
+// will do. `version_req` is a semver requirement like ">=1.2, <2" or "*".
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub struct Dependency {
+    pub name: String,
+    pub version_req: String,
+    // only needed on hosts where this holds
+    pub when: Option<Condition>,
+}
+
+impl Dependency {
+    pub fn applies(&self, facts: Option<&Facts>) -> bool {
+        condition::applies(self.when.as_ref(), facts)
+    }
 }
 
 #[derive(Debug)]
 pub struct Role {
     pub path: PathBuf,
     pub name: String,
-    pub dependencies: Vec<Package>,
-    pub env: Env
+    // the files of every role it includes, directly or not
+    pub includes: Vec<PathBuf>,
+    pub dependencies: Vec<Dependency>,
+    pub env: Env,
+    // from `[env.<package>]` tables, for just that package
+    pub package_env: BTreeMap<String, Env>,
 }
 
 #[derive(Debug)]
@@ -43,6 +88,12 @@ pub struct RunList {
     pub basedir: PathBuf,
     repo: PackageRepository,
     pub roles: Vec<Role>,
+    pub created: Option<i64>,
+    pub expires: Option<i64>,
+    // of the host it's running on, for `when` conditions
+    facts: Option<Facts>,
+    // recorded in archive.toml, see `EnvConflicts`
+    env_conflicts: EnvConflicts,
 }
 
 fn read_toml<P: AsRef<Path>>(path: &P) -> Result<toml::Value> {
@@ -61,6 +112,120 @@ fn read_toml<P: AsRef<Path>>(path: &P) -> Result<toml::Value> {
     }
 }
 
+fn read_dependency(path: &Path, kind: &str, name: &str, table: &toml::Table) -> Result<Dependency> {
+    let error = |message: &str| Error::new(PathBuf::from(path), &format!("{} dependency \"{}\" {}", kind, name, message));
+
+    for key in table.keys() {
+        if ! ["name", "version", "when"].contains(&key.as_str()) {
+            return Err(error(&format!("has an unknown setting `{}`.", key)));
+        }
+    }
+
+    let version_req = match table.get("version") {
+        Some(v) => match v.as_str() {
+            Some(s) => s.to_string(),
+            None => return Err(error("version isn't a string."))
+        },
+        None => "*".to_string()
+    };
+
+    let when = match table.get("when") {
+        Some(v) => match v.as_str() {
+            Some(s) => match Condition::parse(s) {
+                Ok(condition) => Some(condition),
+                Err(e) => return Err(error(&format!("has an invalid `when`: {}.", e)))
+            },
+            None => return Err(error("`when` isn't a string."))
+        },
+        None => None
+    };
+
+    Ok(Dependency{name: name.to_string(), version_req: version_req, when: when})
+}
+
+// Dependencies are either an array of names, which will take any version, or
+// a table of name => version requirement, like `nginx = ">=1.2, <2"`. Either
+// way a dependency can be a table instead, with a `version` and a `when`
+// condition on the host's facts.
+fn read_dependencies(path: &Path, deps: &toml::Value, kind: &str) -> Result<Vec<Dependency>> {
+    let mut v = Vec::new();
+
+    if let Some(slice) = deps.as_slice() {
+        for dep in slice {
+            if let Some(name) = dep.as_str() {
+                v.push(Dependency{name: name.to_string(), version_req: "*".to_string(), when: None});
+            } else if let Some(table) = dep.as_table() {
+                let name = match table.get("name").and_then(|name| name.as_str()) {
+                    Some(name) => name,
+                    None => return Err(Error::new(PathBuf::from(path), &format!("{} dependency {} doesn't have a `name` string.", kind, dep)))
+                };
+                v.push(try!(read_dependency(path, kind, name, table)));
+            } else {
+                return Err(Error::new(PathBuf::from(path), &format!("{} dependency \"{}\" isn't a string.", kind, dep)));
+            }
+        }
+    } else if let Some(table) = deps.as_table() {
+        for (name, version_req) in table {
+            if let Some(version_req) = version_req.as_str() {
+                v.push(Dependency{name: name.clone(), version_req: version_req.to_string(), when: None});
+            } else if let Some(t) = version_req.as_table() {
+                if t.contains_key("name") {
+                    return Err(Error::new(PathBuf::from(path), &format!("{} dependency \"{}\" has an unknown setting `name`.", kind, name)));
+                }
+                v.push(try!(read_dependency(path, kind, name, t)));
+            } else {
+                return Err(Error::new(PathBuf::from(path), &format!("{} dependency \"{}\" version isn't a string.", kind, name)));
+            }
+        }
+    } else {
+        return Err(Error::new(PathBuf::from(path), &format!("{} `dependencies` isn't an array or a table.", kind)));
+    }
+
+    for dep in v.iter() {
+        if dep.version_req.trim() != "*" && version::parse_req(&dep.version_req).is_none() {
+            return Err(Error::new(PathBuf::from(path), &format!("{} dependency \"{}\" has an invalid version requirement \"{}\".", kind, dep.name, dep.version_req)));
+        }
+    }
+
+    Ok(v)
+}
+
+// Scripts only ever see strings, but env values can also be integers,
+// booleans, or arrays of any of those, which are written with their elements
+// separated by spaces.
+pub fn env_value(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Array(ref elements) => {
+            let mut v = Vec::with_capacity(elements.len());
+            for element in elements {
+                match *element {
+                    toml::Value::Array(_) => return None,
+                    _ => match env_value(element) {
+                        Some(s) => v.push(s),
+                        None => return None
+                    }
+                }
+            }
+            Some(v.join(" "))
+        },
+        _ => None
+    }
+}
+
+// Every role's requirement on a package is kept, so the version picked has
+// to satisfy all of them. Only exact repeats, like a role included twice,
+// are dropped.
+fn merge_dependencies(into: &mut Vec<Dependency>, from: Vec<Dependency>) {
+    for dep in from {
+        if ! into.contains(&dep) {
+            into.push(dep);
+        }
+    }
+}
+
 fn is_executable(path: &PathBuf) -> bool {
     let s: String = path.to_str().unwrap().into();
     let result = unsafe { libc::access(CString::new(s).unwrap().as_ptr(), libc::F_OK | libc::X_OK) };
@@ -70,7 +235,50 @@ fn is_executable(path: &PathBuf) -> bool {
 
 impl Package {
     pub fn from_file<P: AsRef<Path>>(basedir: &P, name: &str) -> Result<Package> {
+        Package::from_dir(basedir.as_ref().join(name), name, None)
+    }
+
+    // Load a package from `basedir/name/version/`, for when several versions
+    // of it live side by side.
+    pub fn from_version_dir<P: AsRef<Path>>(basedir: &P, name: &str, version: &str) -> Result<Package> {
+        Package::from_dir(basedir.as_ref().join(name).join(version), name, Some(version))
+    }
+
+    // Every version of a package, newest first. That's either the one in
+    // `basedir/name/` or all of the `basedir/name/<version>/` directories.
+    pub fn versions<P: AsRef<Path>>(basedir: &P, name: &str) -> Result<Vec<Package>> {
         let dir = basedir.as_ref().join(name);
+        if dir.join("package.toml").exists() || ! dir.is_dir() {
+            return Ok(vec![try!(Package::from_file(basedir, name))]);
+        }
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(v) => v,
+            Err(_) => return Err(Error::new(dir, "failure to read directory"))
+        };
+
+        let mut v = Vec::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => return Err(Error::new(dir, "failure to read directory"))
+            };
+            if path.join("package.toml").exists() {
+                if let Some(version) = path.file_name().and_then(|f| f.to_str()) {
+                    v.push(try!(Package::from_version_dir(basedir, name, version)));
+                }
+            }
+        }
+
+        if v.is_empty() {
+            return Err(Error::new(dir.join("package.toml"), "failure to open"));
+        }
+
+        v.sort_by(|a, b| version::newest_first(&a.version, &b.version));
+        Ok(v)
+    }
+
+    fn from_dir(dir: PathBuf, name: &str, dir_version: Option<&str>) -> Result<Package> {
         let config_path = dir.join("package.toml");
         let config = try!(read_toml(&config_path));
 
@@ -102,6 +310,89 @@ impl Package {
             return Err(Error::new(config_path, "package `main` isn't executable."));
         }
 
+        let check = match config.lookup("package.check") {
+            Some(check) => match check.as_str() {
+                Some(str) => Some(dir.join(str)),
+                None => return Err(Error::new(config_path, "package `check` isn't a string."))
+            },
+            None => None
+        };
+
+        if let Some(ref check) = check {
+            if ! check.exists() {
+                return Err(Error::new(config_path, "package `check` doesn't exist."));
+            }
+
+            if ! is_executable(check) {
+                return Err(Error::new(config_path, "package `check` isn't executable."));
+            }
+        }
+
+        let actions = match config.lookup("package.actions") {
+            Some(actions) => match actions.as_table() {
+                Some(table) => {
+                    let mut m = BTreeMap::new();
+
+                    for (k, v) in table {
+                        let action = match v.as_str() {
+                            Some(v) => dir.join(v),
+                            None => return Err(Error::new(config_path, &format!("package action \"{}\" isn't a string.", k)))
+                        };
+
+                        if ! action.exists() {
+                            return Err(Error::new(config_path, &format!("package action \"{}\" doesn't exist.", k)));
+                        }
+
+                        if ! is_executable(&action) {
+                            return Err(Error::new(config_path, &format!("package action \"{}\" isn't executable.", k)));
+                        }
+
+                        m.insert(k.clone(), action);
+                    }
+
+                    m
+                },
+                None => return Err(Error::new(config_path, "package `actions` isn't a table."))
+            },
+            None => BTreeMap::new()
+        };
+
+        let templates = match config.lookup("templates") {
+            Some(templates) => match templates.as_table() {
+                Some(table) => {
+                    let mut m = BTreeMap::new();
+
+                    for (k, v) in table {
+                        // templates come from the package too, so one can't pull in /etc/shadow
+                        let template = PathBuf::from(k);
+                        if template.is_absolute() || template.components().any(|c| c == Component::ParentDir) {
+                            return Err(Error::new(config_path, &format!("package template \"{}\" isn't inside the package.", k)));
+                        }
+
+                        let output = match v.as_str() {
+                            Some(v) => PathBuf::from(v),
+                            None => return Err(Error::new(config_path, &format!("package template \"{}\" output isn't a string.", k)))
+                        };
+
+                        // rendered files go in the package, never somewhere else on the box
+                        if output.is_absolute() || output.components().any(|c| c == Component::ParentDir) {
+                            return Err(Error::new(config_path, &format!("package template \"{}\" output \"{}\" isn't inside the package.", k, output.display())));
+                        }
+
+                        if ! dir.join(k).is_file() {
+                            return Err(Error::new(config_path, &format!("package template \"{}\" doesn't exist.", k)));
+                        }
+
+                        m.insert(dir.join(k), dir.join(output));
+                    }
+
+                    m
+                },
+                None => return Err(Error::new(config_path, "package `templates` isn't a table."))
+            },
+            None => BTreeMap::new()
+        };
+
         let version = match config.lookup("package.version") {
             Some(version) => match version.as_str() {
                 Some(str) => str,
@@ -110,17 +401,28 @@ impl Package {
             None => return Err(Error::new(config_path, "package `version` is missing."))
         };
 
+        if dir_version.map(|v| v != version).unwrap_or(false) {
+            return Err(Error::new(config_path, "package `version` doesn't match its directory."));
+        }
+
+        // a var is either just its default, or a table declaring it
+        let mut schema = BTreeMap::new();
         let env = match config.lookup("env") {
             Some(env) => match env.as_table() {
                 Some(table) => {
                     let mut m = Env::new();
 
                     for (k, v) in table {
-                        match v.as_str() {
-                            Some(v) => {
-                                m.insert(k.clone(),v.to_string());
-                            },
-                            None => return Err(Error::new(config_path, &format!("package `env` value \"{}\" isn't a string.", k)))
+                        if let Some(v) = env_value(v) {
+                            m.insert(k.clone(), v);
+                        } else if let Some(t) = v.as_table() {
+                            let (var, default) = try!(schema::Var::from_toml(&config_path, k, t));
+                            if let Some(default) = default {
+                                m.insert(k.clone(), default);
+                            }
+                            schema.insert(k.clone(), var);
+                        } else {
+                            return Err(Error::new(config_path, &format!("package `env` value \"{}\" isn't a string, integer, boolean, array or table.", k)))
                         }
                     }
 
@@ -131,38 +433,84 @@ impl Package {
             None => Env::new()
         };
 
-        let dependencies = match config.lookup("package.dependencies") {
-            Some(deps) => match deps.as_slice() {
-                Some(slice) => {
-                    let mut m = BTreeMap::new();
-
-                    for name in slice {
-                        match name.as_str() {
-                            Some(v) => {
-                                m.insert(v.to_string(), "local".to_string());
-                            },
-                            None => return Err(Error::new(config_path, &format!("package dependency \"{}\" isn't a string.", name)))
-                        };
-
-                    }
-                    m
+        let names = |key: &str| -> Result<Vec<String>> {
+            match config.lookup(&format!("package.{}", key)) {
+                Some(value) => match value.as_slice() {
+                    Some(slice) => {
+                        let mut v = Vec::with_capacity(slice.len());
+                        for name in slice {
+                            match name.as_str() {
+                                Some(name) => v.push(name.to_string()),
+                                None => return Err(Error::new(config_path.clone(), &format!("package `{}` isn't an array of strings.", key)))
+                            }
+                        }
+                        Ok(v)
+                    },
+                    None => Err(Error::new(config_path.clone(), &format!("package `{}` isn't an array of strings.", key)))
                 },
-                None => return Err(Error::new(config_path, "package `dependencies` isn't an array."))
-            },
-            None => BTreeMap::new()
+                None => Ok(vec![])
+            }
         };
+        let provides = try!(names("provides"));
+        let conflicts = try!(names("conflicts"));
+        let after = try!(names("after"));
+        let before = try!(names("before"));
+
+        let mut dependencies = BTreeMap::new();
+        let mut conditions = BTreeMap::new();
+        if let Some(deps) = config.lookup("package.dependencies") {
+            for dep in try!(read_dependencies(&config_path, deps, "package")) {
+                if dependencies.contains_key(&dep.name) {
+                    return Err(Error::new(config_path, &format!("package dependency \"{}\" is listed more than once.", dep.name)));
+                }
+                if let Some(when) = dep.when {
+                    conditions.insert(dep.name.clone(), when);
+                }
+                dependencies.insert(dep.name, dep.version_req);
+            }
+        }
 
         Ok(Package{main: dir.join(main),
+                   check: check,
+                   actions: actions,
+                   templates: templates,
                    dir: dir,
                    name: name.to_string(),
                    version: version.to_string(),
                    env: env,
-                   dependencies: dependencies})
+                   schema: schema,
+                   provides: provides,
+                   conflicts: conflicts,
+                   after: after,
+                   before: before,
+                   dependencies: dependencies,
+                   conditions: conditions})
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
     }
 
     pub fn dependencies(&self) -> &BTreeMap<String, String> {
         &self.dependencies
     }
+
+    pub fn condition(&self, dependency: &str) -> Option<&Condition> {
+        self.conditions.get(dependency)
+    }
+
+    // The dependencies needed on a host with these facts.
+    pub fn dependencies_on(&self, facts: Option<&Facts>) -> Vec<&str> {
+        self.dependencies.keys()
+            .filter(|name| condition::applies(self.conditions.get(name.as_str()), facts))
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    // Whether a role is allowed to set this var.
+    pub fn declares(&self, var: &str) -> bool {
+        self.env.contains_key(var) || self.schema.contains_key(var)
+    }
 }
 
 impl fmt::Display for Package {
@@ -173,55 +521,99 @@ impl fmt::Display for Package {
 
 impl Role {
     fn from_file<P: AsRef<Path>>(basedir: &P, role: &str) -> Result<Role> {
+        Role::from_file_included_by(basedir, role, &mut Vec::new())
+    }
+
+    // `chain` is the roles that (transitively) include this one, so include
+    // cycles can be reported instead of recursing forever.
+    fn from_file_included_by<P: AsRef<Path>>(basedir: &P, role: &str, chain: &mut Vec<String>) -> Result<Role> {
         let role_path = basedir.as_ref().join("roles").join(format!("{}.toml", role));
         let config = try!(read_toml(&role_path));
 
-        let dependencies = match config.lookup("role.dependencies") {
-            Some(deps) => match deps.as_slice() {
+        let include = match config.lookup("role.include") {
+            Some(include) => match include.as_slice() {
                 Some(slice) => {
-                    let mut v: Vec<Package> = Vec::with_capacity(slice.len());
-
+                    let mut v = Vec::with_capacity(slice.len());
                     for name in slice {
                         match name.as_str() {
-                            Some(val) => {
-                                v.push(try!(Package::from_file(basedir, val)));
-                            },
-                            None => return Err(Error::new(role_path, &format!("role dependency \"{}\" isn't a string.", name)))
-                        };
-
+                            Some(name) => v.push(name.to_string()),
+                            None => return Err(Error::new(role_path, &format!("role include \"{}\" isn't a string.", name)))
+                        }
                     }
                     v
                 },
-                None => return Err(Error::new(role_path, "role `dependencies` isn't an array."))
+                None => return Err(Error::new(role_path, "role `include` isn't an array."))
             },
             None => vec![]
         };
 
-        let env = match config.lookup("env") {
-            Some(env) => match env.as_table() {
-                Some(table) => {
-                    let mut m = Env::new();
+        // included roles are merged in order, so later ones win
+        let mut dependencies = Vec::new();
+        let mut env = Env::new();
+        let mut package_env: BTreeMap<String, Env> = BTreeMap::new();
+        let mut includes = Vec::new();
+        chain.push(role.to_string());
+        for name in include {
+            if let Some(i) = chain.iter().position(|r| *r == name) {
+                let mut cycle = chain[i..].to_vec();
+                cycle.push(name);
+                return Err(Error::new(role_path, &format!("role include cycle: {}", cycle.join(" -> "))));
+            }
+
+            let included = try!(Role::from_file_included_by(basedir, &name, chain));
+            merge_dependencies(&mut dependencies, included.dependencies);
+            env.extend(included.env);
+            for (package, vars) in included.package_env {
+                package_env.entry(package).or_insert_with(Env::new).extend(vars);
+            }
+            for path in Some(included.path).into_iter().chain(included.includes) {
+                if ! includes.contains(&path) {
+                    includes.push(path);
+                }
+            }
+        }
+        chain.pop();
+
+        // and then the role's own values win over all of them
+        if let Some(deps) = config.lookup("role.dependencies") {
+            merge_dependencies(&mut dependencies, try!(read_dependencies(&role_path, deps, "role")));
+        }
 
+        match config.lookup("env") {
+            Some(table) => match table.as_table() {
+                Some(table) => {
                     for (k, v) in table {
-                        match v.as_str() {
-                            Some(v) => {
-                                m.insert(k.clone(),v.to_string());
-                            },
-                            None => return Err(Error::new(role_path, "`env` values aren't strings."))
+                        if let Some(v) = env_value(v) {
+                            env.insert(k.clone(), v);
+                        } else if let Some(vars) = v.as_table() {
+                            let scoped = package_env.entry(k.clone()).or_insert_with(Env::new);
+                            for (var, v) in vars {
+                                match env_value(v) {
+                                    Some(v) => {
+                                        scoped.insert(var.clone(), v);
+                                    },
+                                    None => return Err(Error::new(role_path, &format!("`env.{}` value \"{}\" isn't a string, integer, boolean or array.", k, var)))
+                                }
+                            }
+                        } else {
+                            return Err(Error::new(role_path, &format!("`env` value \"{}\" isn't a string, integer, boolean, array or table.", k)))
                         }
                     }
-
-                    m
                 },
                 None => return Err(Error::new(role_path, "`env` key isn't a table."))
             },
-            None => Env::new()
+            None => {}
         };
 
-        Ok(Role{path: role_path, name: role.to_string(), dependencies: dependencies, env: env})
+        Ok(Role{path: role_path,
+                name: role.to_string(),
+                includes: includes,
+                dependencies: dependencies,
+                env: env,
+                package_env: package_env})
     }
 
-    pub fn dependencies(&self) -> &Vec<Package> {
+    pub fn dependencies(&self) -> &Vec<Dependency> {
         &self.dependencies
     }
 
@@ -229,21 +621,11 @@ impl Role {
         &self.env
     }
 
-}
-
-impl Encodable for Role {
-    fn encode<E: Encoder>(&self, e: &mut E) -> result::Result<(), E::Error> {
-        e.emit_map(self.dependencies.len(), |e| {
-            let mut i = 0;
-            for package in self.dependencies.iter() {
-                try!(e.emit_map_elt_key(i, |e| e.emit_str(&package.name)));
-                try!(e.emit_map_elt_val(i, |e| e.emit_str(&package.version)));
-
-                i += 1;
-            }
-            Ok(())
-        })
+    // Overrides for just this package, which win over `env`.
+    pub fn package_env(&self, package: &str) -> Option<&Env> {
+        self.package_env.get(package)
     }
+
 }
 
 impl RunList {
@@ -253,37 +635,72 @@ impl RunList {
             v.push(try!(Role::from_file(basedir, role)));
         }
         Ok(RunList{ basedir: basedir.as_ref().to_path_buf(),
-                    repo: try!(PackageRepository::from_basedir(basedir)),
-                    roles: v })
+                    repo: try!(PackageRepository::for_roles(basedir, &v)),
+                    roles: v,
+                    created: None,
+                    expires: None,
+                    facts: None,
+                    env_conflicts: EnvConflicts::Merge })
     }
 
     pub fn from_archive<P: AsRef<Path>>(basedir: &P) -> Result<RunList> {
         let path = basedir.as_ref().join("archive.toml");
         let archive = try!(read_toml(&path));
-        let roles = match archive.lookup("archive.roles") {
-            Some(roles) => {
-                match roles.as_slice() {
-                    Some(slice) => {
-                        let mut v = Vec::with_capacity(slice.len());
-                        for name in slice {
-                            match name.as_str() {
-                                Some(str) => v.push(str.to_string()),
-                                None => return Err(Error::new(path, "`roles` isn't an array of strings."))
-                            };
-                        }
-                        v
-                    },
-                    None => return Err(Error::new(path, "`roles` key isn't an array."))
-                }
-            },
-            None => vec![]
-        };
+        let metadata = try!(Metadata::from_toml(&path, &archive));
+
+        let mut runlist = try!(RunList::from_roles(basedir, &metadata.roles));
+        runlist.created = metadata.created;
+        runlist.expires = metadata.expires;
+        runlist.handle_env_conflicts(metadata.env_conflicts);
+        Ok(runlist)
+    }
+
+    // See `PackageRepository::for_roles`.
+    pub fn skipped(&self) -> &Vec<PathBuf> {
+        self.repo.skipped()
+    }
+
+    pub fn warnings(&self) -> &Vec<Error> {
+        self.repo.warnings()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        metadata::is_expired(self.expires, metadata::now())
+    }
+
+    // Resolve to these versions where they still fit.
+    pub fn prefer_versions(&mut self, versions: BTreeMap<String, String>) {
+        self.repo.prefer(versions);
+    }
+
+    pub fn handle_env_conflicts(&mut self, how: EnvConflicts) {
+        self.env_conflicts = how;
+        self.repo.handle_env_conflicts(how);
+    }
+
+    pub fn env_conflicts(&self) -> Result<Vec<EnvConflict>> {
+        self.repo.env_conflicts(&self.roles, self.facts.as_ref())
+    }
+
+    // Resolve for the host with these facts, instead of for every host.
+    pub fn use_facts(&mut self, facts: Facts) {
+        self.facts = Some(facts);
+    }
 
-        RunList::from_roles(basedir, &roles)
+    pub fn facts(&self) -> Option<&Facts> {
+        self.facts.as_ref()
     }
 
     pub fn sort_dependencies(&self) -> Result<Vec<Executable>> {
-        self.repo.resolve(&self.roles)
+        self.repo.resolve(&self.roles, self.facts.as_ref())
+    }
+
+    pub fn conditions(&self) -> Result<Vec<Decision>> {
+        self.repo.conditions(&self.roles, self.facts.as_ref())
+    }
+
+    pub fn graph(&self) -> Result<Graph> {
+        self.repo.graph(&self.roles, self.facts.as_ref())
     }
 }
 
@@ -292,7 +709,7 @@ impl Encodable for RunList {
         try!(e.emit_map(1, |e| {
             try!(e.emit_map_elt_key(0, |e| e.emit_str("archive")));
             e.emit_map_elt_val(0, |e| {
-                e.emit_map(1, |e| {
+                e.emit_map(4, |e| {
                     try!(e.emit_map_elt_key(0, |e| e.emit_str("roles")));
                     try!(e.emit_map_elt_val(0, |e| {
                         e.emit_seq(self.roles.len(), |e| {
@@ -305,11 +722,27 @@ impl Encodable for RunList {
                             Ok(())
                         })
                     }));
+                    if let Some(created) = self.created {
+                        try!(e.emit_map_elt_key(1, |e| e.emit_str("created")));
+                        try!(e.emit_map_elt_val(1, |e| e.emit_i64(created)));
+                    }
+                    if let Some(expires) = self.expires {
+                        try!(e.emit_map_elt_key(2, |e| e.emit_str("expires")));
+                        try!(e.emit_map_elt_val(2, |e| e.emit_i64(expires)));
+                    }
+                    try!(e.emit_map_elt_key(3, |e| e.emit_str("env_conflicts")));
+                    try!(e.emit_map_elt_val(3, |e| e.emit_str(self.env_conflicts.name())));
                     Ok(())
                 })
             })
         }));
 
+        // record the version each role's dependencies resolved to
+        let versions = match self.sort_dependencies() {
+            Ok(exes) => exes.into_iter().map(|exe| (exe.name, exe.version)).collect(),
+            Err(_) => BTreeMap::new()
+        };
+
         try!(e.emit_map(1, |e| {
             try!(e.emit_map_elt_key(0, |e| e.emit_str("role")));
             e.emit_map_elt_val(0, |e| {
@@ -317,7 +750,19 @@ impl Encodable for RunList {
                     let mut i = 0;
                     for role in self.roles.iter() {
                         try!(e.emit_map_elt_key(i, |e| e.emit_str(&role.name)));
-                        try!(e.emit_map_elt_val(0, |e| role.encode(e)));
+                        try!(e.emit_map_elt_val(0, |e| {
+                            e.emit_map(role.dependencies.len(), |e| {
+                                let mut j = 0;
+                                for dep in role.dependencies.iter() {
+                                    let version = versions.get(&dep.name).unwrap_or(&dep.version_req);
+                                    try!(e.emit_map_elt_key(j, |e| e.emit_str(&dep.name)));
+                                    try!(e.emit_map_elt_val(j, |e| e.emit_str(version)));
+
+                                    j += 1;
+                                }
+                                Ok(())
+                            })
+                        }));
 
                         i += 1;
                     }
@@ -332,7 +777,8 @@ impl Encodable for RunList {
 
 #[cfg(test)]
 mod tests {
-    use super::{Env, Package};
+    use super::{Dependency, Env, Package, Role};
+    use facts::Facts;
     use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
 
@@ -345,8 +791,17 @@ mod tests {
                 name: name.to_string(),
                 version: version.to_string(),
                 main: basedir.as_ref().join(name).join(main),
+                check: None,
+                actions: BTreeMap::new(),
+                templates: BTreeMap::new(),
                 env: Env::new(),
-                dependencies: BTreeMap::new()}
+                schema: BTreeMap::new(),
+                provides: vec![],
+                conflicts: vec![],
+                after: vec![],
+                before: vec![],
+                dependencies: BTreeMap::new(),
+                conditions: BTreeMap::new()}
     }
 
     #[test]
@@ -367,6 +822,56 @@ mod tests {
         assert_eq!(p, quick_package(basedir, name, "25", "this_is_the_main.sh"));
     }
 
+    #[test]
+    fn package_with_check_specified() {
+        let basedir = resource("package_unit_tests");
+        let name = "check_specified";
+        let p = Package::from_file(&basedir, name).unwrap();
+
+        let mut expected = quick_package(&basedir, name, "17", "main.sh");
+        expected.check = Some(basedir.join(name).join("check.sh"));
+        assert_eq!(p, expected);
+    }
+
+    #[test]
+    fn package_with_actions_specified() {
+        let basedir = resource("package_unit_tests");
+        let name = "actions_specified";
+        let p = Package::from_file(&basedir, name).unwrap();
+
+        let mut expected = quick_package(&basedir, name, "17", "main.sh");
+        expected.actions.insert("uninstall".to_string(), basedir.join(name).join("uninstall.sh"));
+        expected.actions.insert("verify".to_string(), basedir.join(name).join("bin").join("verify.sh"));
+        assert_eq!(p, expected);
+    }
+
+    #[test]
+    fn package_with_templates_specified() {
+        let basedir = resource("package_unit_tests");
+        let name = "templates_specified";
+        let p = Package::from_file(&basedir, name).unwrap();
+
+        let mut expected = quick_package(&basedir, name, "17", "main.sh");
+        expected.templates.insert(basedir.join(name).join("app.conf.tmpl"), basedir.join(name).join("conf").join("app.conf"));
+        expected.env.insert("PORT".to_string(), "80".to_string());
+        assert_eq!(p, expected);
+    }
+
+    #[test]
+    fn package_with_typed_env() {
+        let basedir = resource("package_unit_tests");
+        let name = "typed_env";
+        let p = Package::from_file(&basedir, name).unwrap();
+
+        assert_eq!(p.env["STRING"], "80");
+        assert_eq!(p.env["INTEGER"], "80");
+        assert_eq!(p.env["BOOLEAN"], "false");
+        assert_eq!(p.env["ARRAY"], "one two");
+        assert_eq!(p.env["NUMBERS"], "1 2");
+        assert_eq!(p.env["EMPTY"], "");
+        assert_eq!(p.env["DEFAULT"], "8080");
+    }
+
     #[test]
     fn package_dependencies() {
         let basedir = resource("package_unit_tests").join("dependencies");
@@ -377,24 +882,71 @@ mod tests {
 
         // a depends on b and d
         let mut a_deps = BTreeMap::new();
-        a_deps.insert("pkg_b".to_string(), "local".to_string());
-        a_deps.insert("pkg_d".to_string(), "local".to_string());
+        a_deps.insert("pkg_b".to_string(), "*".to_string());
+        a_deps.insert("pkg_d".to_string(), ">=17".to_string());
         assert_eq!(pkg_a.dependencies(), &a_deps);
 
         // b depends on c
         let mut b_deps = BTreeMap::new();
-        b_deps.insert("pkg_c".to_string(), "local".to_string());
+        b_deps.insert("pkg_c".to_string(), "*".to_string());
         assert_eq!(pkg_b.dependencies(), &b_deps);
 
         // c depends on d
         let mut c_deps = BTreeMap::new();
-        c_deps.insert("pkg_d".to_string(), "local".to_string());
+        c_deps.insert("pkg_d".to_string(), "*".to_string());
         assert_eq!(pkg_c.dependencies(), &c_deps);
 
         // d has no deps
         assert_eq!(0, pkg_d.dependencies.len());
     }
 
+    #[test]
+    fn package_conditional_dependencies() {
+        let basedir = resource("package_unit_tests");
+        let p = Package::from_file(&basedir, "conditional_dependencies").unwrap();
+
+        let mut deps = BTreeMap::new();
+        deps.insert("always".to_string(), "*".to_string());
+        deps.insert("apt".to_string(), "*".to_string());
+        deps.insert("big".to_string(), ">=2".to_string());
+        assert_eq!(p.dependencies(), &deps);
+        assert_eq!(p.condition("always"), None);
+        assert_eq!(p.condition("big").unwrap().to_string(), "memory >= 8G && cpus > 2");
+
+        // without facts every condition counts
+        assert_eq!(p.dependencies_on(None), vec!["always", "apt", "big"]);
+        let facts = Facts{os_family: "debian".to_string(), cpus: 4, memory: 2 << 30, ..Facts::default()};
+        assert_eq!(p.dependencies_on(Some(&facts)), vec!["always", "apt"]);
+
+        let name = "dependency_listed_twice";
+        let err = Package::from_file(&basedir, name).err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package dependency \"apt\" is listed more than once.");
+    }
+
+    #[test]
+    fn package_provides_and_conflicts() {
+        let basedir = resource("package_unit_tests");
+        let p = Package::from_file(&basedir, "provides_and_conflicts").unwrap();
+        assert_eq!(p.provides, vec!["logging-agent".to_string(), "syslog".to_string()]);
+        assert_eq!(p.conflicts, vec!["logging-agent".to_string()]);
+
+        let name = "conflicts_isnt_an_array";
+        let err = Package::from_file(&basedir, name).err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package `conflicts` isn't an array of strings.");
+    }
+
+    #[test]
+    fn package_dependency_when_is_invalid() {
+        let basedir = resource("package_unit_tests");
+        let name = "dependency_when_is_invalid";
+        let err = Package::from_file(&basedir, name).err().unwrap();
+
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert!(err.message().starts_with("package dependency \"big\" has an invalid `when`: there's no fact called `ram`"));
+    }
+
     #[test]
     fn package_env_vars() {
         let basedir = resource("package_unit_tests").join("env");
@@ -555,6 +1107,71 @@ mod tests {
         assert_eq!(err.message(), "package `main` isn't executable.");
     }
 
+    #[test]
+    fn package_check_doesnt_exist() {
+        let basedir = resource("package_unit_tests");
+        let name = "check_doesnt_exist";
+        let p = Package::from_file(&basedir, name);
+
+        assert!(p.is_err());
+
+        let err = p.err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package `check` doesn't exist.");
+    }
+
+    #[test]
+    fn package_check_isnt_executable() {
+        let basedir = resource("package_unit_tests");
+        let name = "check_isnt_executable";
+        let p = Package::from_file(&basedir, name);
+
+        assert!(p.is_err());
+
+        let err = p.err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package `check` isn't executable.");
+    }
+
+    #[test]
+    fn package_action_isnt_executable() {
+        let basedir = resource("package_unit_tests");
+        let name = "action_isnt_executable";
+        let p = Package::from_file(&basedir, name);
+
+        assert!(p.is_err());
+
+        let err = p.err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package action \"uninstall\" isn't executable.");
+    }
+
+    #[test]
+    fn package_template_outside_package() {
+        let basedir = resource("package_unit_tests");
+        let name = "template_outside_package";
+        let p = Package::from_file(&basedir, name);
+
+        assert!(p.is_err());
+
+        let err = p.err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package template \"app.conf.tmpl\" output \"../app.conf\" isn't inside the package.");
+    }
+
+    #[test]
+    fn package_template_source_outside_package() {
+        let basedir = resource("package_unit_tests");
+        let name = "template_source_outside_package";
+        let p = Package::from_file(&basedir, name);
+
+        assert!(p.is_err());
+
+        let err = p.err().unwrap();
+        assert_eq!(err.path(), basedir.join(name).join("package.toml"));
+        assert_eq!(err.message(), "package template \"../../etc/shadow\" isn't inside the package.");
+    }
+
     #[test]
     fn package_version_isnt_a_string() {
         let basedir = resource("package_unit_tests");
@@ -591,7 +1208,7 @@ mod tests {
 
         let err = p.err().unwrap();
         assert_eq!(err.path(), basedir.join(name).join("package.toml"));
-        assert_eq!(err.message(), "package `env` value \"baz\" isn't a string.");
+        assert_eq!(err.message(), "package `env` value \"baz\" isn't a string, integer, boolean, array or table.");
     }
 
     #[test]
@@ -617,7 +1234,7 @@ mod tests {
 
         let err = p.err().unwrap();
         assert_eq!(err.path(), basedir.join(name).join("package.toml"));
-        assert_eq!(err.message(), "package `dependencies` isn't an array.");
+        assert_eq!(err.message(), "package `dependencies` isn't an array or a table.");
     }
 
     #[test]
@@ -632,4 +1249,56 @@ mod tests {
         assert_eq!(err.path(), basedir.join(name).join("package.toml"));
         assert_eq!(err.message(), "failure to parse toml");
     }
+
+    #[test]
+    fn role_with_includes() {
+        let basedir = resource("role_unit_tests");
+        let role = Role::from_file(&basedir, "web").unwrap();
+        let dep = |name: &str, version_req: &str| Dependency{name: name.to_string(), version_req: version_req.to_string(), when: None};
+
+        assert_eq!(role.name, "web");
+        assert_eq!(role.path, basedir.join("roles").join("web.toml"));
+        assert_eq!(role.includes, vec![basedir.join("roles").join("base.toml"), basedir.join("roles").join("monitoring.toml")]);
+
+        // base comes in twice but counts once, and web's own ntp requirement
+        // is added to base's rather than replacing it
+        assert_eq!(role.dependencies, vec![dep("common", "*"), dep("ntp", "*"), dep("collectd", "*"), dep("nginx", "*"), dep("ntp", ">=2")]);
+
+        // monitoring overrides base, and web overrides both
+        let mut env = Env::new();
+        env.insert("LOG_LEVEL".to_string(), "warn".to_string());
+        env.insert("REGION".to_string(), "eu".to_string());
+        assert_eq!(role.env, env);
+
+        // and the same goes for `[env.<package>]` tables, var by var
+        let mut nginx = Env::new();
+        nginx.insert("PORT".to_string(), "8080".to_string());
+        nginx.insert("WORKERS".to_string(), "4".to_string());
+        assert_eq!(role.package_env("nginx"), Some(&nginx));
+        assert_eq!(role.package_env("common"), None);
+    }
+
+    #[test]
+    fn role_with_conditional_dependencies() {
+        let basedir = resource("role_unit_tests");
+        let role = Role::from_file(&basedir, "conditional").unwrap();
+
+        // arrays of tables keep their order, unlike tables
+        let names = role.dependencies.iter().map(|d| d.name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["common", "apt", "apk"]);
+        assert_eq!(role.dependencies[2].version_req, ">=3");
+
+        let facts = Facts{os: "alpine".to_string(), os_family: "alpine".to_string(), ..Facts::default()};
+        let applies = role.dependencies.iter().map(|d| d.applies(Some(&facts))).collect::<Vec<bool>>();
+        assert_eq!(applies, vec![true, false, true]);
+    }
+
+    #[test]
+    fn role_include_cycle() {
+        let basedir = resource("role_unit_tests");
+        let err = Role::from_file(&basedir, "cycle_a").err().unwrap();
+
+        assert_eq!(err.path(), basedir.join("roles").join("cycle_c.toml"));
+        assert_eq!(err.message(), "role include cycle: cycle_a -> cycle_b -> cycle_c -> cycle_a");
+    }
 }
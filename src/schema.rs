@@ -0,0 +1,181 @@
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::result;
+use toml;
+
+use error::{Error, Result};
+use runlist;
+
+// What a package declares about one of its env vars, from a table like
+//
+//     [env.PORT]
+//     description = "port to listen on"
+//     default = "80"
+//     integer = true
+//
+// The default itself lives in the package's `env` like any other value.
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Var {
+    pub description: Option<String>,
+    pub required: bool,
+    pub regex: Option<String>,
+    pub choices: Option<Vec<String>>,
+    pub integer: bool,
+}
+
+fn anchored(regex: &str) -> result::Result<Regex, String> {
+    Regex::new(&format!("^(?:{})$", regex)).map_err(|e| e.to_string())
+}
+
+impl Var {
+    // Returns the declaration and its default, if it has one.
+    pub fn from_toml<P: AsRef<Path>>(path: P, name: &str, table: &toml::Table) -> Result<(Var, Option<String>)> {
+        let error = |message: &str| Error::new(PathBuf::from(path.as_ref()), &format!("package env \"{}\" {}", name, message));
+        let string = |key: &str| -> Result<Option<String>> {
+            match table.get(key) {
+                Some(v) => match v.as_str() {
+                    Some(s) => Ok(Some(s.to_string())),
+                    None => Err(error(&format!("`{}` isn't a string.", key)))
+                },
+                None => Ok(None)
+            }
+        };
+        let boolean = |key: &str| -> Result<bool> {
+            match table.get(key) {
+                Some(v) => match v.as_bool() {
+                    Some(b) => Ok(b),
+                    None => Err(error(&format!("`{}` isn't a boolean.", key)))
+                },
+                None => Ok(false)
+            }
+        };
+
+        for key in table.keys() {
+            if ! ["description", "required", "default", "regex", "enum", "integer"].contains(&key.as_str()) {
+                return Err(error(&format!("has an unknown setting `{}`.", key)));
+            }
+        }
+
+        let choices = match table.get("enum") {
+            Some(v) => match v.as_slice() {
+                Some(slice) => {
+                    let mut choices = Vec::with_capacity(slice.len());
+                    for choice in slice {
+                        match choice.as_str() {
+                            Some(s) => choices.push(s.to_string()),
+                            None => return Err(error("`enum` isn't an array of strings."))
+                        }
+                    }
+                    Some(choices)
+                },
+                None => return Err(error("`enum` isn't an array of strings."))
+            },
+            None => None
+        };
+
+        let var = Var{description: try!(string("description")),
+                      required: try!(boolean("required")),
+                      regex: try!(string("regex")),
+                      choices: choices,
+                      integer: try!(boolean("integer"))};
+        let default = match table.get("default") {
+            Some(v) => match runlist::env_value(v) {
+                Some(s) => Some(s),
+                None => return Err(error("`default` isn't a string, integer, boolean or array."))
+            },
+            None => None
+        };
+
+        if let Some(ref regex) = var.regex {
+            if let Err(e) = anchored(regex) {
+                return Err(error(&format!("has an invalid regex: {}", e)));
+            }
+        }
+
+        if var.required && default.is_some() {
+            return Err(error("is required, so it can't have a default."));
+        }
+
+        if let Some(ref default) = default {
+            if let Err(e) = var.validate(default) {
+                return Err(error(&format!("has a default \"{}\", which {}.", default, e)));
+            }
+        }
+
+        Ok((var, default))
+    }
+
+    // Err says what's wrong with the value, e.g. "isn't an integer".
+    pub fn validate(&self, value: &str) -> result::Result<(), String> {
+        if self.integer && value.parse::<i64>().is_err() {
+            return Err("isn't an integer".to_string());
+        }
+
+        if let Some(ref choices) = self.choices {
+            if ! choices.iter().any(|c| c == value) {
+                let quoted = choices.iter().map(|c| format!("\"{}\"", c)).collect::<Vec<String>>();
+                return Err(format!("isn't one of {}", quoted.join(", ")));
+            }
+        }
+
+        if let Some(ref regex) = self.regex {
+            if ! try!(anchored(regex)).is_match(value) {
+                return Err(format!("doesn't match /{}/", regex));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Var;
+    use toml;
+
+    fn var(s: &str) -> Result<(Var, Option<String>), String> {
+        let value: toml::Value = s.parse().unwrap();
+        Var::from_toml("package.toml", "PORT", value.as_table().unwrap()).map_err(|e| e.message().to_string())
+    }
+
+    #[test]
+    fn from_toml() {
+        let (v, default) = var("description = \"port\"\ndefault = \"80\"\ninteger = true\n").unwrap();
+        assert_eq!(v, Var{description: Some("port".to_string()), integer: true, ..Var::default()});
+        assert_eq!(default, Some("80".to_string()));
+
+        let (v, default) = var("required = true\nenum = [\"a\", \"b\"]\n").unwrap();
+        assert_eq!(v, Var{required: true, choices: Some(vec!["a".to_string(), "b".to_string()]), ..Var::default()});
+        assert_eq!(default, None);
+    }
+
+    #[test]
+    fn from_toml_errors() {
+        assert_eq!(var("requried = true\n").err().unwrap(), "package env \"PORT\" has an unknown setting `requried`.");
+        assert_eq!(var("required = \"yes\"\n").err().unwrap(), "package env \"PORT\" `required` isn't a boolean.");
+        assert_eq!(var("required = true\ndefault = \"80\"\n").err().unwrap(), "package env \"PORT\" is required, so it can't have a default.");
+        assert_eq!(var("default = 8.5\n").err().unwrap(), "package env \"PORT\" `default` isn't a string, integer, boolean or array.");
+        assert_eq!(var("integer = true\ndefault = \"eighty\"\n").err().unwrap(),
+                   "package env \"PORT\" has a default \"eighty\", which isn't an integer.");
+        assert!(var("regex = \"(\"\n").err().unwrap().starts_with("package env \"PORT\" has an invalid regex"));
+    }
+
+    #[test]
+    fn validate() {
+        let v = Var{integer: true, ..Var::default()};
+        assert!(v.validate("-17").is_ok());
+        assert_eq!(v.validate("17.5"), Err("isn't an integer".to_string()));
+
+        let v = Var{choices: Some(vec!["dev".to_string(), "prod".to_string()]), ..Var::default()};
+        assert!(v.validate("prod").is_ok());
+        assert_eq!(v.validate("staging"), Err("isn't one of \"dev\", \"prod\"".to_string()));
+
+        // the whole value has to match
+        let v = Var{regex: Some("[a-z]+|[0-9]+".to_string()), ..Var::default()};
+        assert!(v.validate("abc").is_ok());
+        assert!(v.validate("123").is_ok());
+        assert_eq!(v.validate("abc123"), Err("doesn't match /[a-z]+|[0-9]+/".to_string()));
+
+        assert!(Var::default().validate("anything").is_ok());
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::result;
+
+use error::{Error, Result};
+use runlist::Env;
+
+// Templates are plain text where `${NAME}` is replaced with the value of
+// NAME from the package's env, and `$${` is a literal `${`. Any other `$` is
+// left alone so shell and nginx style variables pass straight through.
+#[derive(Debug, PartialEq)]
+pub enum Piece<'a> {
+    Text(&'a str),
+    Var(&'a str),
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => false
+    }
+}
+
+fn line_of(s: &str, i: usize) -> usize {
+    s[..i].matches('\n').count() + 1
+}
+
+pub fn parse<'a, P: AsRef<Path>>(path: P, s: &'a str) -> Result<Vec<Piece<'a>>> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while let Some(found) = s[i..].find('$') {
+        let at = i + found;
+        let rest = &s[at..];
+
+        if rest.starts_with("$${") {
+            pieces.push(Piece::Text(&s[start..at]));
+            start = at + 1;
+            i = at + 3;
+        } else if rest.starts_with("${") {
+            let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+            let name = match line.find('}') {
+                Some(end) => &rest[2..end],
+                None => return Err(Error::new(PathBuf::from(path.as_ref()), &format!("line {}: `${{` isn't closed.", line_of(s, at))))
+            };
+            if ! is_name(name) {
+                return Err(Error::new(PathBuf::from(path.as_ref()), &format!("line {}: \"{}\" isn't a valid variable name.", line_of(s, at), name)));
+            }
+
+            pieces.push(Piece::Text(&s[start..at]));
+            pieces.push(Piece::Var(name));
+            start = at + name.len() + 3;
+            i = start;
+        } else {
+            i = at + 1;
+        }
+    }
+    pieces.push(Piece::Text(&s[start..]));
+
+    Ok(pieces.into_iter().filter(|p| *p != Piece::Text("")).collect())
+}
+
+pub fn render<P: AsRef<Path>>(path: P, s: &str, env: &Env) -> Result<String> {
+    let mut rendered = String::with_capacity(s.len());
+    for piece in try!(parse(&path, s)) {
+        match piece {
+            Piece::Text(text) => rendered.push_str(text),
+            Piece::Var(name) => match env.get(name) {
+                Some(value) => rendered.push_str(value),
+                None => return Err(Error::new(PathBuf::from(path.as_ref()), &format!("variable \"{}\" isn't defined.", name)))
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut f = match File::open(path.as_ref()) {
+        Ok(v) => v,
+        Err(_) => return Err(Error::new(PathBuf::from(path.as_ref()), "failure to open"))
+    };
+    let mut s = String::new();
+    if f.read_to_string(&mut s).is_err() {
+        return Err(Error::new(PathBuf::from(path.as_ref()), "failure to read - perhaps invalid UTF-8?"));
+    }
+    Ok(s)
+}
+
+pub fn render_file<P: AsRef<Path>>(path: P, env: &Env) -> Result<String> {
+    let s = try!(read_file(&path));
+    render(path, &s, env)
+}
+
+fn lookup<'a>(name: &str, scopes: &[&'a Env]) -> Option<&'a String> {
+    scopes.iter().filter_map(|env| env.get(name)).next()
+}
+
+fn expand(name: &str, value: &str, scopes: &[&Env], expanded: &mut Env, chain: &mut Vec<String>) -> result::Result<String, (String, String)> {
+    if let Some(v) = expanded.get(name) {
+        return Ok(v.clone());
+    }
+    if let Some(i) = chain.iter().position(|n| n == name) {
+        let mut cycle = chain[i..].to_vec();
+        cycle.push(name.to_string());
+        return Err((chain[i].clone(), format!("refers to itself through {}", cycle.join(" -> "))));
+    }
+
+    let pieces = match parse(name, value) {
+        Ok(v) => v,
+        Err(e) => return Err((name.to_string(), e.message().to_string()))
+    };
+
+    chain.push(name.to_string());
+    let mut s = String::with_capacity(value.len());
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => s.push_str(text),
+            Piece::Var(var) => match lookup(var, scopes) {
+                Some(v) => s.push_str(&try!(expand(var, v, scopes, expanded, chain))),
+                None => return Err((name.to_string(), format!("refers to \"{}\", which isn't defined.", var)))
+            }
+        }
+    }
+    chain.pop();
+
+    expanded.insert(name.to_string(), s.clone());
+    Ok(s)
+}
+
+// Expand `${NAME}` references in env values, with the same syntax as
+// templates. Values can refer to anything in `scopes`, which are searched in
+// order, and those are expanded too. Errors are the var that couldn't be
+// expanded and why.
+pub fn expand_env(env: &Env, scopes: &[&Env]) -> result::Result<Env, (String, String)> {
+    let mut expanded = Env::new();
+    let mut result = Env::new();
+    for (k, v) in env.iter() {
+        result.insert(k.clone(), try!(expand(k, v, scopes, &mut expanded, &mut Vec::new())));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_env, parse, render, Piece};
+    use runlist::Env;
+
+    fn env() -> Env {
+        let mut env = Env::new();
+        env.insert("PORT".to_string(), "8080".to_string());
+        env.insert("HOST_NAME".to_string(), "example.com".to_string());
+        env
+    }
+
+    #[test]
+    fn renders_variables() {
+        assert_eq!(render("t", "listen ${PORT};\nserver_name ${HOST_NAME};\n", &env()).unwrap(),
+                   "listen 8080;\nserver_name example.com;\n");
+        assert_eq!(render("t", "${PORT}${PORT}", &env()).unwrap(), "80808080");
+        assert_eq!(render("t", "", &env()).unwrap(), "");
+    }
+
+    #[test]
+    fn leaves_other_dollars_alone() {
+        assert_eq!(render("t", "proxy_set_header Host $host; cost $5 $", &env()).unwrap(),
+                   "proxy_set_header Host $host; cost $5 $");
+        assert_eq!(render("t", "echo $${PORT} ${PORT}", &env()).unwrap(), "echo ${PORT} 8080");
+        assert_eq!(parse("t", "a$${b").unwrap(), vec![Piece::Text("a"), Piece::Text("${b")]);
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let err = render("conf/app.conf.tmpl", "${PORT} ${MISSING}", &env()).err().unwrap();
+        assert_eq!(err.to_string(), "conf/app.conf.tmpl: variable \"MISSING\" isn't defined.");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let err = render("t", "one\ntwo ${PORT\n", &env()).err().unwrap();
+        assert_eq!(err.message(), "line 2: `${` isn't closed.");
+
+        let err = render("t", "${PORT}\n\n${not valid}", &env()).err().unwrap();
+        assert_eq!(err.message(), "line 3: \"not valid\" isn't a valid variable name.");
+
+        let err = render("t", "${}", &env()).err().unwrap();
+        assert_eq!(err.message(), "line 1: \"\" isn't a valid variable name.");
+    }
+
+    fn quick_env(vars: Vec<(&str, &str)>) -> Env {
+        vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn expands_env() {
+        let env = quick_env(vec![("URL", "http://${HOST}:${PORT}/"), ("HOST", "${NAME}.example.com"), ("PORT", "80"), ("RAW", "$${PORT}")]);
+        let role = quick_env(vec![("NAME", "www"), ("PORT", "8080")]);
+        let builtins = quick_env(vec![("TSH_ROLE", "web")]);
+
+        // the env comes first, then the role, then built-ins
+        let expanded = expand_env(&env, &[&env, &role, &builtins]).unwrap();
+        assert_eq!(expanded, quick_env(vec![("URL", "http://www.example.com:80/"), ("HOST", "www.example.com"), ("PORT", "80"), ("RAW", "${PORT}")]));
+
+        let env = quick_env(vec![("WHERE", "/srv/${TSH_ROLE}")]);
+        assert_eq!(expand_env(&env, &[&env, &builtins]).unwrap()["WHERE"], "/srv/web");
+    }
+
+    #[test]
+    fn expand_env_errors() {
+        let env = quick_env(vec![("URL", "http://${HOTS}/")]);
+        assert_eq!(expand_env(&env, &[&env]).err().unwrap(),
+                   ("URL".to_string(), "refers to \"HOTS\", which isn't defined.".to_string()));
+
+        let env = quick_env(vec![("A", "${B}"), ("B", "x${C}"), ("C", "${A}"), ("D", "fine")]);
+        assert_eq!(expand_env(&env, &[&env]).err().unwrap(),
+                   ("A".to_string(), "refers to itself through A -> B -> C -> A".to_string()));
+
+        let env = quick_env(vec![("A", "${A}")]);
+        assert_eq!(expand_env(&env, &[&env]).err().unwrap(),
+                   ("A".to_string(), "refers to itself through A -> A".to_string()));
+
+        let env = quick_env(vec![("A", "${A")]);
+        assert_eq!(expand_env(&env, &[&env]).err().unwrap(),
+                   ("A".to_string(), "line 1: `${` isn't closed.".to_string()));
+    }
+}
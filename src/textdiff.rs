@@ -0,0 +1,142 @@
+use std::cmp;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+// One step of the edit script, along with where it happens in each file.
+#[derive(Debug, Clone, Copy)]
+struct Edit {
+    op: Op,
+    old: usize,
+    new: usize,
+}
+
+// Classic longest common subsequence table walk. It's quadratic, but the
+// files that end up in packages are small config files and scripts.
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut v = Vec::with_capacity(n + m);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            v.push(Edit{op: Op::Equal, old: i, new: j});
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            v.push(Edit{op: Op::Delete, old: i, new: j});
+            i += 1;
+        } else {
+            v.push(Edit{op: Op::Insert, old: i, new: j});
+            j += 1;
+        }
+    }
+    v
+}
+
+fn range(start: usize, count: usize) -> String {
+    // an empty range is reported as starting at the line before it
+    let start = if count == 0 { start } else { start + 1 };
+    if count == 1 {
+        format!("{}", start)
+    } else {
+        format!("{},{}", start, count)
+    }
+}
+
+// Produce a unified diff of two texts, or None if they're the same.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str, context: usize) -> Option<String> {
+    let old_lines = old.lines().collect::<Vec<&str>>();
+    let new_lines = new.lines().collect::<Vec<&str>>();
+    let edits = edits(&old_lines, &new_lines);
+
+    let changes = edits.iter().enumerate()
+        .filter(|&(_, e)| e.op != Op::Equal)
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    if changes.is_empty() {
+        return None;
+    }
+
+    // group changes that are close enough to share context
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &i in changes.iter() {
+        let start = i.saturating_sub(context);
+        let end = cmp::min(edits.len(), i + context + 1);
+        let merged = match hunks.last_mut() {
+            Some(last) if start <= last.1 => {
+                last.1 = end;
+                true
+            },
+            _ => false
+        };
+        if ! merged {
+            hunks.push((start, end));
+        }
+    }
+
+    let mut s = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks {
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|e| e.op != Op::Insert).count();
+        let new_count = hunk.iter().filter(|e| e.op != Op::Delete).count();
+        s.push_str(&format!("@@ -{} +{} @@\n", range(hunk[0].old, old_count), range(hunk[0].new, new_count)));
+        for e in hunk {
+            match e.op {
+                Op::Equal => s.push_str(&format!(" {}\n", old_lines[e.old])),
+                Op::Delete => s.push_str(&format!("-{}\n", old_lines[e.old])),
+                Op::Insert => s.push_str(&format!("+{}\n", new_lines[e.new])),
+            }
+        }
+    }
+    Some(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unified;
+
+    #[test]
+    fn identical() {
+        assert_eq!(unified("a", "b", "one\ntwo\n", "one\ntwo\n", 3), None);
+        assert_eq!(unified("a", "b", "", "", 3), None);
+    }
+
+    #[test]
+    fn single_change() {
+        assert_eq!(unified("a", "b", "one\ntwo\nthree\n", "one\n2\nthree\n", 3).unwrap(),
+                   "--- a\n+++ b\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n");
+    }
+
+    #[test]
+    fn added_and_removed_files() {
+        assert_eq!(unified("a", "b", "", "one\ntwo\n", 3).unwrap(),
+                   "--- a\n+++ b\n@@ -0,0 +1,2 @@\n+one\n+two\n");
+        assert_eq!(unified("a", "b", "one\n", "", 3).unwrap(),
+                   "--- a\n+++ b\n@@ -1 +0,0 @@\n-one\n");
+    }
+
+    #[test]
+    fn separate_hunks() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "one\n2\n3\n4\n5\n6\n7\n8\n9\nten\n";
+        assert_eq!(unified("a", "b", old, new, 1).unwrap(),
+                   "--- a\n+++ b\n@@ -1,2 +1,2 @@\n-1\n+one\n 2\n@@ -9,2 +9,2 @@\n 9\n-10\n+ten\n");
+        // with enough context they merge into one
+        assert_eq!(unified("a", "b", old, new, 4).unwrap().matches("@@ -").count(), 1);
+    }
+}
@@ -1,69 +1,279 @@
 use flate2::read::GzDecoder;
 use rustc_serialize::base64::FromBase64;
 use sodiumoxide::crypto::sign;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::os::unix::fs::PermissionsExt;
+use std::result;
 use tar;
 
-pub fn unpack<R: Read>(input: &mut R, pubkey: sign::PublicKey) -> io::Result<Vec<u8>>{
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    InvalidHeader,
+    InvalidSignatureEncoding(String),
+    SignatureMismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifyError::Io(ref e) => write!(f, "{}", e),
+            VerifyError::InvalidHeader => write!(f, "Invalid Archive Header"),
+            VerifyError::InvalidSignatureEncoding(ref e) => write!(f, "{}", e),
+            VerifyError::SignatureMismatch => write!(f, "Signature Does Not Match"),
+        }
+    }
+}
+
+impl From<VerifyError> for io::Error {
+    fn from(e: VerifyError) -> io::Error {
+        match e {
+            VerifyError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+pub fn verify<R: Read>(input: &mut R, pubkey: &sign::PublicKey) -> result::Result<Vec<u8>, VerifyError> {
     // Read & Verify Package Header
     let mut identifier_bytes = [0u8; 8];
-    try!(input.read_exact(&mut identifier_bytes));
+    if let Err(e) = input.read_exact(&mut identifier_bytes) {
+        return Err(match e.kind() {
+            io::ErrorKind::UnexpectedEof => VerifyError::InvalidHeader,
+            _ => VerifyError::Io(e),
+        });
+    }
 
     if &identifier_bytes != b"TURBOv01" {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid Archive Header"));
+        return Err(VerifyError::InvalidHeader);
     }
 
     // Read Signature
     let mut signature_bytes_base64 = [0u8; 88];
-    try!(input.read_exact(&mut signature_bytes_base64));
+    if let Err(e) = input.read_exact(&mut signature_bytes_base64) {
+        return Err(match e.kind() {
+            io::ErrorKind::UnexpectedEof => VerifyError::InvalidSignatureEncoding("Truncated Signature".to_string()),
+            _ => VerifyError::Io(e),
+        });
+    }
     let signature_bytes = match (&signature_bytes_base64).from_base64() {
         Ok(value) => value,
         Err(e) => {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            return Err(VerifyError::InvalidSignatureEncoding(e.to_string()));
         }
     };
     let signature = match sign::Signature::from_slice(&signature_bytes) {
         Some(value) => value,
         None => {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid Signature"));
+            return Err(VerifyError::InvalidSignatureEncoding("Invalid Signature".to_string()));
         }
     };
 
     // Read Tarball
     let mut tarball_bytes = Vec::new();
-    try!(input.read_to_end(&mut tarball_bytes));
+    if let Err(e) = input.read_to_end(&mut tarball_bytes) {
+        return Err(VerifyError::Io(e));
+    }
 
     // Verify Signature
-    if ! sign::verify_detached(&signature, &tarball_bytes, &pubkey) {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Signature Does Not Match"));
+    if ! sign::verify_detached(&signature, &tarball_bytes, pubkey) {
+        return Err(VerifyError::SignatureMismatch);
     }
 
     return Ok(tarball_bytes);
 }
 
-pub fn explode<R: Read, P: AsRef<Path>>(input: R, basedir: P) {
-    let decoder = GzDecoder::new(input).unwrap();
+pub fn unpack<R: Read>(input: &mut R, pubkey: sign::PublicKey) -> io::Result<Vec<u8>>{
+    verify(input, &pubkey).map_err(io::Error::from)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Only plain, relative paths that stay inside the archive are allowed.
+fn is_safe_path(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+// Walk every entry of the tarball, making sure each one is a plain file or
+// directory with a safe path, and hand back the contents of `name`.
+pub fn read_file<R: Read, P: AsRef<Path>>(input: R, name: P) -> io::Result<Option<Vec<u8>>> {
+    let decoder = try!(GzDecoder::new(input));
+    let mut found = None;
+
+    for iter_entry in try!(tar::Archive::new(decoder).entries()) {
+        let mut entry = try!(iter_entry);
+        let path = try!(entry.path()).into_owned();
 
-    for iter_entry in tar::Archive::new(decoder).entries().unwrap() {
-        let mut entry = iter_entry.unwrap();
-        let new_path = PathBuf::from(basedir.as_ref()).join(&entry.path().unwrap());
+        if ! is_safe_path(&path) {
+            return Err(invalid_data(format!("unsafe path {} in archive", path.display())));
+        }
+
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => {},
+            tar::EntryType::Regular => {
+                let mut contents = Vec::new();
+                try!(entry.read_to_end(&mut contents));
+                if path == name.as_ref() {
+                    found = Some(contents);
+                }
+            },
+            default @ _ => {
+                return Err(invalid_data(format!("unknown entry_type {:?} for {}", default, path.display())));
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+// Refuse to follow any symlink that already exists between `basedir` and the
+// entry we're about to write, so an archive can't escape `basedir` through one.
+fn check_for_symlinks(basedir: &Path, path: &Path) -> io::Result<()> {
+    let mut current = PathBuf::from(basedir);
+    for component in path.components() {
+        current.push(component.as_os_str());
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) => {
+                if metadata.file_type().is_symlink() {
+                    return Err(invalid_data(format!("refusing to write through symlink {}", current.display())));
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub fn explode<R: Read, P: AsRef<Path>>(input: R, basedir: P) -> io::Result<()> {
+    let decoder = try!(GzDecoder::new(input));
+
+    for iter_entry in try!(tar::Archive::new(decoder).entries()) {
+        let mut entry = try!(iter_entry);
+        let path = try!(entry.path()).into_owned();
+
+        if ! is_safe_path(&path) {
+            return Err(invalid_data(format!("unsafe path {} in archive", path.display())));
+        }
+        try!(check_for_symlinks(basedir.as_ref(), &path));
+
+        let new_path = PathBuf::from(basedir.as_ref()).join(&path);
+        // never hand out setuid, setgid or sticky bits
+        let mode = try!(entry.header().mode()) & 0o777;
 
         match entry.header().entry_type() {
             tar::EntryType::Directory => {
-                fs::create_dir(&new_path).unwrap();
-                fs::set_permissions(&new_path, fs::Permissions::from_mode(entry.header().mode().unwrap())).unwrap();
+                if ! new_path.is_dir() {
+                    try!(fs::create_dir(&new_path));
+                }
+                try!(fs::set_permissions(&new_path, fs::Permissions::from_mode(mode)));
             },
             tar::EntryType::Regular => {
-                let mut outfile = fs::File::create(&new_path).unwrap();
-                io::copy(&mut entry, &mut outfile).unwrap();
-                fs::set_permissions(&new_path, fs::Permissions::from_mode(entry.header().mode().unwrap())).unwrap();
+                let mut outfile = try!(fs::File::create(&new_path));
+                try!(io::copy(&mut entry, &mut outfile));
+                try!(fs::set_permissions(&new_path, fs::Permissions::from_mode(mode)));
             },
-            default @ _ => panic!("unknown entry_type {:?}", default),
+            default @ _ => {
+                return Err(invalid_data(format!("unknown entry_type {:?} for {}", default, path.display())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{explode, read_file};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tar;
+    use tempdir::TempDir;
+
+    fn tarball(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut gzipper = GzEncoder::new(&mut bytes, Compression::Default);
+            {
+                let mut builder = tar::Builder::new(&mut gzipper);
+                for &(path, entry_type, contents) in entries {
+                    let mut header = tar::Header::new_gnu();
+                    // write the name by hand so that unsafe paths make it in
+                    {
+                        let name = &mut header.as_old_mut().name;
+                        name[..path.len()].copy_from_slice(path.as_bytes());
+                    }
+                    header.set_size(contents.len() as u64);
+                    header.set_mode(0o755);
+                    header.set_entry_type(entry_type);
+                    header.set_cksum();
+                    builder.append(&header, io::Cursor::new(contents)).unwrap();
+                }
+                builder.finish().unwrap();
+            }
+            gzipper.finish().unwrap();
         }
+        bytes
+    }
+
+    #[test]
+    fn explode_plain_archive() {
+        let dir = TempDir::new("unpack").unwrap();
+        let bytes = tarball(&[("a/", tar::EntryType::dir(), b""),
+                              ("a/main.sh", tar::EntryType::file(), b"#!/bin/sh\n")]);
+        explode(bytes.as_slice(), dir.path()).unwrap();
+        assert!(dir.path().join("a").is_dir());
+        assert!(dir.path().join("a").join("main.sh").is_file());
+        assert_eq!(read_file(bytes.as_slice(), "a/main.sh").unwrap().unwrap(), b"#!/bin/sh\n");
+        assert_eq!(read_file(bytes.as_slice(), "b/main.sh").unwrap(), None);
+    }
+
+    #[test]
+    fn explode_refuses_unsafe_paths() {
+        let dir = TempDir::new("unpack").unwrap();
+        let inner = dir.path().join("inner");
+        fs::create_dir(&inner).unwrap();
+
+        let bytes = tarball(&[("../escaped", tar::EntryType::file(), b"uh oh")]);
+        assert!(explode(bytes.as_slice(), &inner).is_err());
+        assert!(read_file(bytes.as_slice(), "archive.toml").is_err());
+        assert!(!dir.path().join("escaped").exists());
+
+        let bytes = tarball(&[("/tmp/escaped", tar::EntryType::file(), b"uh oh")]);
+        assert!(explode(bytes.as_slice(), &inner).is_err());
+    }
+
+    #[test]
+    fn explode_refuses_links() {
+        let dir = TempDir::new("unpack").unwrap();
+        let bytes = tarball(&[("link", tar::EntryType::symlink(), b"")]);
+        assert!(explode(bytes.as_slice(), dir.path()).is_err());
+        assert!(read_file(bytes.as_slice(), "archive.toml").is_err());
+    }
+
+    #[test]
+    fn explode_refuses_to_write_through_symlinks() {
+        let dir = TempDir::new("unpack").unwrap();
+        let inner = dir.path().join("inner");
+        let outside = dir.path().join("outside");
+        fs::create_dir(&inner).unwrap();
+        fs::create_dir(&outside).unwrap();
+        symlink(&outside, inner.join("a")).unwrap();
 
+        let bytes = tarball(&[("a/main.sh", tar::EntryType::file(), b"uh oh")]);
+        assert!(explode(bytes.as_slice(), &inner).is_err());
+        assert!(!outside.join("main.sh").exists());
     }
 }
@@ -0,0 +1,80 @@
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+
+// Package versions are semver, but plenty of packages predate that and use
+// something like "17". Short numeric versions are padded out to "17.0.0".
+pub fn parse(s: &str) -> Option<Version> {
+    if let Ok(v) = Version::parse(s) {
+        return Some(v);
+    }
+
+    let parts = s.split('.').collect::<Vec<&str>>();
+    if parts.len() > 2 || parts.iter().any(|p| p.is_empty() || !p.chars().all(|c| c.is_digit(10))) {
+        return None;
+    }
+    let mut padded = parts.join(".");
+    for _ in parts.len()..3 {
+        padded.push_str(".0");
+    }
+    Version::parse(&padded).ok()
+}
+
+pub fn parse_req(s: &str) -> Option<VersionReq> {
+    VersionReq::parse(s).ok()
+}
+
+// "*" matches any version at all, even ones that aren't semver.
+pub fn matches(req: &str, version: &str) -> bool {
+    if req.trim() == "*" {
+        return true;
+    }
+    match (parse_req(req), parse(version)) {
+        (Some(req), Some(version)) => req.matches(&version),
+        _ => false
+    }
+}
+
+// Newest first. Versions that don't parse sort after the ones that do.
+pub fn newest_first(a: &str, b: &str) -> Ordering {
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => b.cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, newest_first, parse};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn lenient_parsing() {
+        assert_eq!(parse("1.2.3").unwrap().to_string(), "1.2.3");
+        assert_eq!(parse("17").unwrap().to_string(), "17.0.0");
+        assert_eq!(parse("1.2").unwrap().to_string(), "1.2.0");
+        assert!(parse("local").is_none());
+        assert!(parse("1..2").is_none());
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn requirements() {
+        assert!(matches(">=1.2, <2", "1.2.0"));
+        assert!(matches(">=1.2, <2", "1.9.9"));
+        assert!(!matches(">=1.2, <2", "2.0.0"));
+        assert!(!matches(">=1.2, <2", "1.1.0"));
+        assert!(matches("17", "17"));
+        assert!(matches("*", "local"));
+        assert!(!matches("^1", "local"));
+        assert!(!matches("not a requirement", "1.0.0"));
+    }
+
+    #[test]
+    fn ordering() {
+        assert_eq!(newest_first("2.0.0", "1.10.0"), Ordering::Less);
+        assert_eq!(newest_first("1.2.0", "1.10.0"), Ordering::Greater);
+        assert_eq!(newest_first("1.0.0", "local"), Ordering::Less);
+    }
+}
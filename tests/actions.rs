@@ -0,0 +1,83 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileOptions, run};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("actions").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("actions"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["web".to_string()],
+                                      ..Default::default()}),
+               0);
+
+    let run_action = |action: &str| {
+        let mut output = io::Cursor::new(Vec::new());
+        let code = run(&mut output, action.to_string(), pubkey.clone(), path_string(&archive), "".to_string());
+        (code, String::from_utf8(output.into_inner()).unwrap())
+    };
+
+    // without --action every package's main runs, including tool from the
+    // base role that web includes
+    let (code, output) = run_action("");
+    assert_eq!(code, 0);
+    assert!(output.starts_with("Running: tool, base, app\n"));
+    assert!(output.contains("base main\n"));
+
+    ////////////////////////////////////////////////////////////////
+    // packages without the action are skipped, the rest in order //
+    ////////////////////////////////////////////////////////////////
+    let (code, output) = run_action("install");
+    assert_eq!(code, 0);
+    assert!(output.starts_with("Running install: base\n"));
+    assert!(output.contains("base install\n"));
+    assert!(!output.contains("main\n"));
+
+    /////////////////////////////////////
+    // uninstall runs in reverse order //
+    /////////////////////////////////////
+    let (code, output) = run_action("uninstall");
+    assert_eq!(code, 0);
+    assert!(output.starts_with("Running uninstall: app, base\n"));
+    assert!(output.find("app uninstall\n").unwrap() < output.find("base uninstall\n").unwrap());
+    assert!(output.ends_with("\nSummary:\n  app: changed\n  base: changed\n2 changed, 0 unchanged, 0 failed\n"));
+
+    // a failing action fails the run
+    let (code, output) = run_action("verify");
+    assert_eq!(code, 1);
+    assert!(output.contains("app verify\n"));
+    assert!(output.ends_with("0 changed, 0 unchanged, 1 failed\n"));
+
+    // and an action nobody has does nothing
+    let (code, output) = run_action("upgrade");
+    assert_eq!(code, 0);
+    assert!(output.ends_with("0 changed, 0 unchanged, 0 failed\n"));
+}
@@ -0,0 +1,28 @@
+// Helpers shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn resource<P: AsRef<Path>>(path: P) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("resources").join(path)
+}
+
+pub fn path_string<P: AsRef<Path>>(path: P) -> String {
+    path.as_ref().to_str().unwrap().to_string()
+}
+
+// compile writes turboshell.lock into its basedir, so tests compile a copy
+// of their fixture rather than the fixture itself.
+pub fn copy_dir<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) {
+    fs::create_dir_all(to.as_ref()).unwrap();
+    for entry in fs::read_dir(from.as_ref()).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.as_ref().join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(entry.path(), target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
@@ -0,0 +1,70 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileFormat, CompileOptions, inspect, run};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("conditions").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("conditions"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let compile_to = |format: CompileFormat, path: &Path| {
+        compile(&mut io::Cursor::new(Vec::new()),
+                CompileOptions{basedir: path_string(&basedir),
+                               output: path_string(path),
+                               seedfile: path_string(&seedfile),
+                               format: format,
+                               roles: vec!["host".to_string()],
+                               ..Default::default()})
+    };
+
+    // the archive has every package, whatever the host turns out to be
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile_to(CompileFormat::Archive, &archive), 0);
+
+    ///////////////////////////////////////////////
+    // conditions are checked on the host at run //
+    ///////////////////////////////////////////////
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(run(&mut output, "".to_string(), pubkey.clone(), path_string(&archive), "".to_string()), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.starts_with("Running: always, present\n"));
+    assert!(!output.contains("absent main"));
+
+    /////////////////////////////////////////
+    // and inspect --plan shows which held //
+    /////////////////////////////////////////
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(inspect(&mut output, true, "".to_string(), pubkey.clone(), path_string(&archive)), 0);
+    assert_eq!(String::from_utf8(output.into_inner()).unwrap(),
+               "Conditions:\n  \
+                role host depends on present when cpus >= 1 && hostname == \"*\": true\n  \
+                role host depends on absent when os == \"no-such-os\": false\n\
+                Plan: always, present\n");
+
+    // sh scripts have no facts to check conditions against
+    assert_eq!(compile_to(CompileFormat::Shell, &tempdir.path().join("host.sh")), 1);
+}
@@ -0,0 +1,126 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileOptions, diff};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("diff").unwrap();
+    let old_basedir = tempdir.path().join("old");
+    copy_dir(resource("integration_tests").join("diff").join("old"), &old_basedir);
+    let new_basedir = tempdir.path().join("new");
+    copy_dir(resource("integration_tests").join("diff").join("new"), &new_basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let old_archive = tempdir.path().join("old.tsar");
+    let new_archive = tempdir.path().join("new.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&old_basedir),
+                                      output: path_string(&old_archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["first".to_string(), "second".to_string()],
+                                      ..Default::default()}),
+               0);
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&new_basedir),
+                                      output: path_string(&new_archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["first".to_string(), "third".to_string()],
+                                      ..Default::default()}),
+               0);
+
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(diff(&mut output, pubkey.clone(), path_string(&old_archive), path_string(&new_archive)), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    let expected = r#"Roles:
+  + third
+  - second
+Packages:
+  ~ a 17 -> 18
+  + c 1
+Dependencies:
+  + a -> c
+  + role third -> b
+  - role second -> b
+Files:
+  M a/main.sh
+--- a/a/main.sh
++++ b/a/main.sh
+@@ -1,3 +1,4 @@
+ #!/bin/sh
+ 
+ echo a
++echo a has changed
+  M a/package.toml
+--- a/a/package.toml
++++ b/a/package.toml
+@@ -1,4 +1,4 @@
+ [package]
+ name = "a"
+-version = "17"
+-dependencies = ["common"]
++version = "18"
++dependencies = ["common", "c"]
+  + c/
+  + c/main.sh
+--- a/c/main.sh
++++ b/c/main.sh
+@@ -0,0 +1,3 @@
++#!/bin/sh
++
++echo c
+  + c/package.toml
+--- a/c/package.toml
++++ b/c/package.toml
+@@ -0,0 +1,3 @@
++[package]
++name = "c"
++version = "1"
+  - roles/second.toml
+--- a/roles/second.toml
++++ b/roles/second.toml
+@@ -1,3 +0,0 @@
+-[role]
+-name = "second"
+-dependencies = ["b"]
+  + roles/third.toml
+--- a/roles/third.toml
++++ b/roles/third.toml
+@@ -0,0 +1,3 @@
++[role]
++name = "third"
++dependencies = ["b"]
+"#;
+    assert!(output.ends_with(expected), "unexpected diff:\n{}", output);
+
+    // an archive compared with itself has no changes
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(diff(&mut output, pubkey.clone(), path_string(&old_archive), path_string(&old_archive)), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.ends_with("Roles:\nPackages:\nDependencies:\nFiles:\n"));
+
+    // archives that don't verify are refused
+    let (other_pk, _) = SeedFile::new().keypair();
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(diff(&mut output, other_pk.as_ref().to_base64(base64::STANDARD), path_string(&old_archive), path_string(&new_archive)), 1);
+}
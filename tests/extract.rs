@@ -0,0 +1,80 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileOptions, extract, run};
+use turboshell::runlist::RunList;
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("extract").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("diff").join("old"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["first".to_string(), "second".to_string()],
+                                      ..Default::default()}),
+               0);
+
+    //////////////////////////////////////
+    // extract into a missing directory //
+    //////////////////////////////////////
+    let extracted = tempdir.path().join("extracted");
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(extract(&mut output, false, pubkey.clone(), path_string(&archive), path_string(&extracted)), 0);
+    assert!(extracted.join("archive.toml").is_file());
+    assert!(extracted.join("roles").join("first.toml").is_file());
+    assert!(extracted.join("a").join("main.sh").is_file());
+
+    // the tree is usable as-is
+    let runlist = RunList::from_archive(&extracted.canonicalize().unwrap()).unwrap();
+    assert_eq!(runlist.sort_dependencies().unwrap().iter().map(|exe| exe.name.as_str()).collect::<Vec<&str>>(),
+               vec!["common", "a", "b"]);
+
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(run(&mut output, "".to_string(), "".to_string(), "".to_string(), path_string(&extracted)), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.starts_with("Running: common, a, b\n"));
+
+    /////////////////////////////////////////////
+    // refuse a non-empty directory by default //
+    /////////////////////////////////////////////
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(extract(&mut output, false, pubkey.clone(), path_string(&archive), path_string(&extracted)), 1);
+
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(extract(&mut output, true, pubkey.clone(), path_string(&archive), path_string(&extracted)), 0);
+
+    ////////////////////////////////////////////
+    // nothing is written for a bad signature //
+    ////////////////////////////////////////////
+    let (other_pk, _) = SeedFile::new().keypair();
+    let unverified = tempdir.path().join("unverified");
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(extract(&mut output, false, other_pk.as_ref().to_base64(base64::STANDARD), path_string(&archive), path_string(&unverified)), 1);
+    assert!(!unverified.exists());
+}
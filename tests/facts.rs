@@ -0,0 +1,80 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use rustc_serialize::json::Json;
+use turboshell::commands::{compile, CompileOptions, extract, facts, run};
+use turboshell::facts::Facts;
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("facts").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("facts"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    // templates can use facts even though they aren't known at compile time
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["host".to_string()],
+                                      ..Default::default()}),
+               0);
+
+    let extracted = tempdir.path().join("extracted");
+    assert_eq!(extract(&mut io::Cursor::new(Vec::new()), false, pubkey.clone(), path_string(&archive), path_string(&extracted)), 0);
+
+    ///////////////////////////////////////////////////////
+    // packages see the facts in their env and templates //
+    ///////////////////////////////////////////////////////
+    let host = Facts::gather();
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(run(&mut output, "".to_string(), "".to_string(), "".to_string(), path_string(&extracted)), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.contains(&format!("hostname {}\n", host.hostname)));
+    assert!(output.contains(&format!("cpus {}\n", host.cpus)));
+    assert!(output.contains(&format!("host = {}\n", host.hostname)));
+
+    // and they're left in the run dir
+    let mut s = String::new();
+    fs::File::open(extracted.join("facts.json")).unwrap().read_to_string(&mut s).unwrap();
+    let json = Json::from_str(&s).unwrap();
+    assert_eq!(json.find("hostname").unwrap().as_string(), Some(host.hostname.as_str()));
+    assert_eq!(json.find("cpus").unwrap().as_u64(), Some(host.cpus));
+
+    ///////////////
+    // tsh facts //
+    ///////////////
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(facts(&mut output, false), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.contains(&format!("TSH_FACT_HOSTNAME={}\n", host.hostname)));
+    assert!(output.contains(&format!("TSH_FACT_CPUS={}\n", host.cpus)));
+
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(facts(&mut output, true), 0);
+    let json = Json::from_str(&String::from_utf8(output.into_inner()).unwrap()).unwrap();
+    assert_eq!(json.find("kernel").unwrap().as_string(), Some(host.kernel.as_str()));
+    assert!(json.find("ip_addresses").unwrap().is_array());
+}
@@ -0,0 +1,83 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use rustc_serialize::json::Json;
+use turboshell::commands::{compile, CompileOptions, graph};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("graph").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("graph"), &basedir);
+
+    let graph_string = |json: bool, pubkey: &str, archive: &str, directory: &str, roles: Vec<String>| {
+        let mut output = io::Cursor::new(Vec::new());
+        assert_eq!(graph(&mut output, json, pubkey.to_string(), archive.to_string(), directory.to_string(), roles), 0);
+        String::from_utf8(output.into_inner()).unwrap()
+    };
+
+    ///////////////////////////////
+    // DOT, straight from source //
+    ///////////////////////////////
+    let dot = graph_string(false, "", "", &path_string(&basedir), vec!["web".to_string()]);
+    assert_eq!(dot,
+               "digraph turboshell {\n  \
+                \"role web\" [shape=box];\n  \
+                \"role web\" -> \"app\";\n  \
+                \"db\" [label=\"1. db 9.4.0\"];\n  \
+                \"cache\" [label=\"2. cache 1.4.0\"];\n  \
+                \"app\" [label=\"3. app 1.0.0\"];\n  \
+                \"app\" -> \"cache\" [label=\"memory >= 2G\", style=dashed];\n  \
+                \"app\" -> \"db\";\n\
+                }\n");
+
+    //////////
+    // JSON //
+    //////////
+    let json = Json::from_str(&graph_string(true, "", "", &path_string(&basedir), vec!["web".to_string()])).unwrap();
+    let packages = json.find("packages").unwrap().as_array().unwrap();
+    assert_eq!(packages.len(), 3);
+    assert_eq!(packages[2].find("name").unwrap().as_string(), Some("app"));
+    assert_eq!(packages[2].find("order").unwrap().as_u64(), Some(3));
+    let dependencies = packages[2].find("dependencies").unwrap().as_array().unwrap();
+    assert_eq!(dependencies[0].find("when").unwrap().as_string(), Some("memory >= 2G"));
+    assert!(dependencies[1].find("when").is_none());
+    assert_eq!(json.find_path(&["roles"]).unwrap().as_array().unwrap()[0].find("name").unwrap().as_string(), Some("web"));
+
+    //////////////////////////////////////
+    // the same from a verified archive //
+    //////////////////////////////////////
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["web".to_string()],
+                                      ..Default::default()}), 0);
+    assert_eq!(graph_string(false, &pubkey, &path_string(&archive), "", vec![]), dot);
+
+    // missing roles are reported like compile does
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(graph(&mut output, false, "".to_string(), "".to_string(), path_string(&basedir), vec!["nope".to_string()]), 1);
+}
@@ -0,0 +1,53 @@
+extern crate turboshell;
+extern crate tempdir;
+
+use turboshell::runlist::RunList;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const PACKAGES: usize = 5000;
+
+// Every package depends on a few later ones, so the role reaches all of
+// them, many times over.
+fn generate<P: AsRef<Path>>(basedir: P) {
+    let basedir = basedir.as_ref();
+    fs::create_dir(basedir.join("roles")).unwrap();
+    fs::File::create(basedir.join("roles").join("large.toml")).unwrap()
+        .write_all(b"[role]\nname = \"large\"\ndependencies = [\"pkg0\"]\n").unwrap();
+
+    for i in 0..PACKAGES {
+        let dir = basedir.join(format!("pkg{}", i));
+        fs::create_dir(&dir).unwrap();
+        let mut deps = [i + 1, 2 * i + 1, 3 * i + 2].iter()
+            .filter(|&&j| j < PACKAGES)
+            .map(|j| format!("\"pkg{}\"", j))
+            .collect::<Vec<String>>();
+        deps.dedup();
+        let mut f = fs::File::create(dir.join("package.toml")).unwrap();
+        write!(f, "[package]\nname = \"pkg{}\"\nversion = \"1.0.{}\"\ndependencies = [{}]\n\n[env]\nPORT = {}\n", i, i, deps.join(", "), i).unwrap();
+        fs::File::create(dir.join("main.sh")).unwrap();
+        fs::set_permissions(dir.join("main.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+// Too slow to generate on every run:
+// cargo test --release --test large_repository -- --ignored
+#[test]
+#[ignore]
+fn main() {
+    let tempdir = tempdir::TempDir::new("large_repository").unwrap();
+    generate(tempdir.path());
+
+    let runlist = RunList::from_roles(&tempdir.path(), &vec!["large".to_string()]).unwrap();
+    let exes = runlist.sort_dependencies().unwrap();
+
+    assert_eq!(exes.len(), PACKAGES);
+    assert_eq!(exes.last().unwrap().name, "pkg0");
+    assert_eq!(exes[0].name, format!("pkg{}", PACKAGES - 1));
+
+    // there are far too many paths to the last package to list them all
+    let graph = runlist.graph().unwrap();
+    assert_eq!(graph.paths(&format!("pkg{}", PACKAGES - 1), 20).len(), 20);
+}
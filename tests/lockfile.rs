@@ -0,0 +1,83 @@
+extern crate turboshell;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use turboshell::commands::{compile, CompileOptions};
+use turboshell::lockfile::Lockfile;
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+fn locked_version<P: AsRef<Path>>(basedir: P, name: &str) -> Option<String> {
+    Lockfile::from_path(basedir.as_ref().join("turboshell.lock")).unwrap()
+        .and_then(|lockfile| lockfile.packages.get(name).map(|p| p.version.clone()))
+}
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("lockfile").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("versions"), &basedir);
+
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(SeedFile::new().to_string().unwrap().as_bytes()).unwrap();
+
+    let archive = tempdir.path().join("archive.tsar");
+    let compile_web = |locked: bool| {
+        compile(&mut io::Cursor::new(Vec::new()),
+                CompileOptions{basedir: path_string(&basedir),
+                               output: path_string(&archive),
+                               seedfile: path_string(&seedfile),
+                               locked: locked,
+                               roles: vec!["web".to_string()],
+                               ..Default::default()})
+    };
+
+    // there's no lockfile to check against until compile writes one
+    assert_eq!(compile_web(true), 1);
+    assert_eq!(compile_web(false), 0);
+    assert_eq!(compile_web(true), 0);
+    assert_eq!(locked_version(&basedir, "nginx").unwrap(), "1.0.0");
+
+    ////////////////////////////////////////////////////////
+    // a newer version that fits doesn't replace the lock //
+    ////////////////////////////////////////////////////////
+    copy_dir(basedir.join("nginx").join("1.0.0"), basedir.join("nginx").join("1.5.0"));
+    fs::File::create(basedir.join("nginx").join("1.5.0").join("package.toml")).unwrap()
+        .write_all(b"[package]\nname = \"nginx\"\nversion = \"1.5.0\"\n").unwrap();
+
+    assert_eq!(compile_web(false), 0);
+    assert_eq!(locked_version(&basedir, "nginx").unwrap(), "1.0.0");
+    assert_eq!(compile_web(true), 0);
+
+    /////////////////////////////////////////////////////
+    // --locked notices a package that changed in place //
+    /////////////////////////////////////////////////////
+    fs::File::create(basedir.join("nginx").join("1.0.0").join("main.sh")).unwrap()
+        .write_all(b"#!/bin/sh\n\necho sneaky\n").unwrap();
+    fs::remove_file(&archive).unwrap();
+
+    assert_eq!(compile_web(true), 1);
+    assert!(!archive.exists());
+
+    // compiling without it updates the lockfile, after which it's happy again
+    assert_eq!(compile_web(false), 0);
+    assert_eq!(compile_web(true), 0);
+
+    ///////////////////////////////////////////////////
+    // without a lockfile the newest version is used //
+    ///////////////////////////////////////////////////
+    fs::remove_file(basedir.join("turboshell.lock")).unwrap();
+    assert_eq!(compile_web(true), 1);
+    assert_eq!(compile_web(false), 0);
+    assert_eq!(locked_version(&basedir, "nginx").unwrap(), "1.5.0");
+}
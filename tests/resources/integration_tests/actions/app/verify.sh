@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo "app verify"
+exit 1
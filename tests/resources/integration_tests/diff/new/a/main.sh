@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo a
+echo a has changed
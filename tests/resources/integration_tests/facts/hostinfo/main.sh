@@ -0,0 +1,5 @@
+#!/bin/sh
+
+echo "hostname $TSH_FACT_HOSTNAME"
+echo "cpus $TSH_FACT_CPUS"
+cat conf/host
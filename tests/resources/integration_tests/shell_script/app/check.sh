@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo "app check port = $PORT"
+exit 1
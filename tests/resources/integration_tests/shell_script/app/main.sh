@@ -0,0 +1,8 @@
+#!/bin/sh
+
+echo "app port = $PORT"
+cat conf/app.conf
+test -f conf/empty && echo "empty exists"
+cat conf/app.ini
+
+echo "app runs on $TSH_FACT_KERNEL with $TSH_FACT_CPUS cpus and $TSH_FACT_MEMORY bytes"
@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo "common greeting = $GREETING"
+echo "common cwd = $(basename "$PWD")"
@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo fails
+exit 3
@@ -0,0 +1,4 @@
+#!/bin/sh
+
+echo "installed check in $(basename "$PWD")"
+exit 0
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo app 1.0.0
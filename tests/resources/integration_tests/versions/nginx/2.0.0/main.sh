@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo nginx 2.0.0
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo lib 1.0.0
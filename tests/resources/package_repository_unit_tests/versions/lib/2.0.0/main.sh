@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo lib 2.0.0
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo modern 3.0.0
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo nginx 1.4.0
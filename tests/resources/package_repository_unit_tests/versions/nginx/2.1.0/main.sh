@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo nginx 2.1.0
@@ -0,0 +1,3 @@
+#!/bin/sh
+
+echo util 1.0.0
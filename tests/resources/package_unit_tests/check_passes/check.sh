@@ -0,0 +1,3 @@
+#!/bin/sh
+
+test -n "$ALREADY_DONE"
@@ -0,0 +1,84 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::bundle;
+use turboshell::commands::{compile, CompileFormat, CompileOptions};
+use turboshell::seedfile::SeedFile;
+use turboshell::unpack;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("self_contained").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("diff").join("old"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+
+    /////////////////////////////////////
+    // compile a self-contained bundle //
+    /////////////////////////////////////
+    let compiled = tempdir.path().join("compiled");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&compiled),
+                                      seedfile: path_string(&seedfile),
+                                      format: CompileFormat::SelfContained,
+                                      roles: vec!["first".to_string(), "second".to_string()],
+                                      ..Default::default()}),
+               0);
+    assert!(fs::metadata(&compiled).unwrap().permissions().mode() & 0o111 != 0);
+
+    let bundle = bundle::from_path(&compiled).unwrap().unwrap();
+    assert!(unpack::verify(&mut bundle.archive.as_slice(), &pk).is_ok());
+
+    ///////////////////////////////////////////////
+    // attach the archive to the real tsh binary //
+    ///////////////////////////////////////////////
+    let mut runtime = Vec::new();
+    fs::File::open(env!("CARGO_BIN_EXE_turboshell")).unwrap().read_to_end(&mut runtime).unwrap();
+
+    let executable = tempdir.path().join("deploy");
+    {
+        let mut f = fs::File::create(&executable).unwrap();
+        bundle::write(&mut f, &runtime, &bundle.archive).unwrap();
+    }
+    fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // runs with the signer's key
+    let output = Command::new(&executable).arg("-k").arg(pk.as_ref().to_base64(base64::STANDARD)).output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("Running: common, a, b\n"));
+
+    // but won't take one on trust when none was built in
+    if option_env!("TSH_TRUSTED_KEY").is_none() {
+        let output = Command::new(&executable).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stdout).unwrap().is_empty());
+        assert!(String::from_utf8(output.stderr).unwrap().contains("-k KEY"));
+    }
+
+    // but not with the wrong one
+    let (other_pk, _) = SeedFile::new().keypair();
+    let output = Command::new(&executable).arg("-k").arg(other_pk.as_ref().to_base64(base64::STANDARD)).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().is_empty());
+}
@@ -0,0 +1,97 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileFormat, CompileOptions, run};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("shell_script").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("shell_script"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let compile_to = |format: CompileFormat, path: &Path, role: &str| {
+        compile(&mut io::Cursor::new(Vec::new()),
+                CompileOptions{basedir: path_string(&basedir),
+                               output: path_string(path),
+                               seedfile: path_string(&seedfile),
+                               format: format,
+                               roles: vec![role.to_string()],
+                               ..Default::default()})
+    };
+
+    ////////////////////////////////////////////
+    // the script does what `tsh run` would do //
+    ////////////////////////////////////////////
+    let archive = tempdir.path().join("web.tsar");
+    let script = tempdir.path().join("web.sh");
+    assert_eq!(compile_to(CompileFormat::Archive, &archive, "web"), 0);
+    assert_eq!(compile_to(CompileFormat::Shell, &script, "web"), 0);
+    assert!(fs::metadata(&script).unwrap().permissions().mode() & 0o111 != 0);
+
+    let mut expected = io::Cursor::new(Vec::new());
+    assert_eq!(run(&mut expected, "".to_string(), pubkey.clone(), path_string(&archive), "".to_string()), 0);
+    let expected = String::from_utf8(expected.into_inner()).unwrap();
+    assert!(expected.contains("app port = 8080\n"));
+
+    // templates are rendered with the role's env before main runs
+    assert!(expected.contains("port = 8080\nhost = $host\nliteral = ${PORT}\nos = "));
+
+    // and both they and main get the host's facts, which the script gathers itself
+    assert!(!expected.contains("os =  "));
+    assert!(!expected.contains("with 0 cpus"));
+
+    // app's check fails so its main runs, installed's passes so it's skipped
+    assert!(expected.contains("app check port = 8080\n"));
+    assert!(expected.contains("installed check in installed\ncheck passed, skipping main\n"));
+    assert!(!expected.contains("installed main ran"));
+    assert!(expected.ends_with("\nSummary:\n  common: changed\n  app: changed\n  installed: unchanged\n2 changed, 1 unchanged, 0 failed\n"));
+
+    let output = Command::new("sh").arg(&script).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+
+    ///////////////////////////////////////////
+    // a failing package stops the run there //
+    ///////////////////////////////////////////
+    let script = tempdir.path().join("broken.sh");
+    assert_eq!(compile_to(CompileFormat::Shell, &script, "broken"), 0);
+
+    let output = Command::new("sh").arg(&script).output().unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Running: fails, common, app\n"));
+    assert!(stdout.contains("fails\n"));
+    assert!(!stdout.contains("common greeting"));
+    assert!(stdout.ends_with("\nSummary:\n  fails: failed\n0 changed, 0 unchanged, 1 failed\n"));
+
+    ///////////////////////////////////////////////////////
+    // templates using undefined variables don't compile //
+    ///////////////////////////////////////////////////////
+    let archive = tempdir.path().join("bad_template.tsar");
+    assert_eq!(compile_to(CompileFormat::Archive, &archive, "bad_template"), 1);
+    assert!(!archive.exists());
+}
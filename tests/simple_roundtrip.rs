@@ -2,15 +2,15 @@ extern crate turboshell;
 extern crate sodiumoxide;
 extern crate tempdir;
 
-use turboshell::commands::{keytool, compile, inspect, run};
+mod common;
+
+use turboshell::commands::{keytool, compile, CompileOptions, inspect, run};
+use turboshell::resolve::EnvConflicts;
+use common::{copy_dir, resource};
 use std::fs;
 use std::io;
 use std::io::Read;
-use std::path::{Path, PathBuf};
-
-fn resource<P: AsRef<Path>>(path: P) -> PathBuf {
-    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("resources").join(path)
-}
+use std::path::PathBuf;
 
 #[test]
 fn main() {
@@ -22,6 +22,8 @@ fn main() {
     // check for expected side effects
 
     let tempdir = tempdir::TempDir::new("simple_roundtrip").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("simple_roundtrip"), &basedir);
     let test_output_file = PathBuf::from("/tmp").join("turboshell_integration_tests_simple_roundtrip");
     let _ = fs::remove_file(&test_output_file);
     assert!(!test_output_file.exists());
@@ -55,10 +57,11 @@ fn main() {
     let archive_path = tempdir.path().join("archive.tsar");
     let mut output = io::Cursor::new(Vec::new());
     assert_eq!(compile(&mut output,
-                       resource("integration_tests").join("simple_roundtrip").to_str().unwrap().to_string(),
-                       archive_path.to_str().unwrap().to_string(),
-                       seedfile.to_str().unwrap().to_string(),
-                       vec!["first".to_string(), "second".to_string()]),
+                       CompileOptions{basedir: basedir.to_str().unwrap().to_string(),
+                                      output: archive_path.to_str().unwrap().to_string(),
+                                      seedfile: seedfile.to_str().unwrap().to_string(),
+                                      roles: vec!["first".to_string(), "second".to_string()],
+                                      ..Default::default()}),
                0);
     assert!(archive_path.exists());
 
@@ -67,6 +70,7 @@ fn main() {
     /////////////////////////
     let mut output = io::Cursor::new(Vec::new());
     assert_eq!(inspect(&mut output,
+                       false,
                        "".to_string(),
                        pubkey.clone(),
                        archive_path.to_str().unwrap().to_string()),
@@ -78,15 +82,17 @@ fn main() {
     let mut output = io::Cursor::new(Vec::new());
     assert!(!test_output_file.exists());
     assert_eq!(run(&mut output,
+                   "".to_string(),
                    pubkey.clone(),
-                   archive_path.to_str().unwrap().to_string()),
+                   archive_path.to_str().unwrap().to_string(),
+                   "".to_string()),
                0);
     assert!(test_output_file.exists());
 
     /////////////////////////////////////
     // check for expected side effects //
     /////////////////////////////////////
-    let mut f = fs::File::open(test_output_file).unwrap();
+    let mut f = fs::File::open(&test_output_file).unwrap();
     let mut output = String::new();
     f.read_to_string(&mut output).unwrap();
     assert_eq!(output, r#"common foo = foo from first role
@@ -97,4 +103,39 @@ b foo = foo from second role
 b bar = bar from package
 "#);
 
+    ///////////////////////////////////////////////////
+    // or run common once for each role that uses it //
+    ///////////////////////////////////////////////////
+    let compile_with = |env_conflicts: EnvConflicts| {
+        compile(&mut io::Cursor::new(Vec::new()),
+                CompileOptions{basedir: basedir.to_str().unwrap().to_string(),
+                               output: archive_path.to_str().unwrap().to_string(),
+                               seedfile: seedfile.to_str().unwrap().to_string(),
+                               env_conflicts: env_conflicts,
+                               roles: vec!["first".to_string(), "second".to_string()],
+                               ..Default::default()})
+    };
+    assert_eq!(compile_with(EnvConflicts::PerRole), 0);
+    fs::remove_file(&test_output_file).unwrap();
+    assert_eq!(run(&mut io::Cursor::new(Vec::new()),
+                   "".to_string(),
+                   pubkey.clone(),
+                   archive_path.to_str().unwrap().to_string(),
+                   "".to_string()),
+               0);
+    let mut output = String::new();
+    fs::File::open(&test_output_file).unwrap().read_to_string(&mut output).unwrap();
+    assert_eq!(output, r#"common foo = foo from first role
+common bar =
+a foo = foo from first role
+a bar = bar from first role
+common foo = foo from second role
+common bar =
+b foo = foo from second role
+b bar = bar from package
+"#);
+    fs::remove_file(&test_output_file).unwrap();
+
+    // the roles disagree about common's FOO
+    assert_eq!(compile_with(EnvConflicts::Error), 1);
 }
@@ -0,0 +1,142 @@
+extern crate turboshell;
+extern crate flate2;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tar;
+extern crate tempdir;
+
+mod common;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use sodiumoxide::crypto::sign;
+use turboshell::commands::{compile, CompileOptions, verify};
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+fn read_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let mut v = Vec::new();
+    fs::File::open(path).unwrap().read_to_end(&mut v).unwrap();
+    v
+}
+
+fn write_bytes<P: AsRef<Path>>(path: P, bytes: &[u8]) {
+    fs::File::create(path).unwrap().write_all(bytes).unwrap();
+}
+
+// A signed archive holding just an archive.toml, for what compile won't make.
+fn signed_archive(sk: &sign::SecretKey, archive_toml: &str) -> Vec<u8> {
+    let mut tarball = Vec::new();
+    {
+        let mut gzipper = GzEncoder::new(&mut tarball, Compression::Default);
+        {
+            let mut builder = tar::Builder::new(&mut gzipper);
+            let mut header = tar::Header::new_gnu();
+            header.set_path("archive.toml").unwrap();
+            header.set_size(archive_toml.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, archive_toml.as_bytes()).unwrap();
+            builder.finish().unwrap();
+        }
+        gzipper.finish().unwrap();
+    }
+
+    let signature = sign::sign_detached(&tarball, sk);
+    let mut bytes = b"TURBOv01".to_vec();
+    bytes.extend_from_slice(signature.as_ref().to_base64(base64::STANDARD).as_bytes());
+    bytes.extend_from_slice(&tarball);
+    bytes
+}
+
+fn verify_archive<P: AsRef<Path>>(pubkey: &str, archive: P, json: bool) -> (i32, String) {
+    let mut output = io::Cursor::new(Vec::new());
+    let exit_code = verify(&mut output, json, pubkey.to_string(), path_string(archive));
+    (exit_code, String::from_utf8(output.into_inner()).unwrap())
+}
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("verify").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("simple_roundtrip"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    write_bytes(&seedfile, seed.to_string().unwrap().as_bytes());
+    let (pk, sk) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["first".to_string(), "second".to_string()],
+                                      ..Default::default()}),
+               0);
+    let original = read_bytes(&archive);
+
+    // a good archive
+    let (exit_code, output) = verify_archive(&pubkey, &archive, false);
+    assert_eq!(exit_code, 0);
+    assert!(output.contains("roles:   first, second"));
+
+    let (exit_code, output) = verify_archive(&pubkey, &archive, true);
+    assert_eq!(exit_code, 0);
+    assert!(output.contains("\"status\": \"ok\""));
+    assert!(output.contains(&format!("\"signer\": \"{}\"", pubkey)));
+
+    // a broken header
+    let tampered = tempdir.path().join("tampered.tsar");
+    let mut bytes = original.clone();
+    bytes[5] = b'x';
+    write_bytes(&tampered, &bytes);
+    assert_eq!(verify_archive(&pubkey, &tampered, false).0, 2);
+
+    // a signature that isn't base64
+    let mut bytes = original.clone();
+    bytes[10] = b'!';
+    write_bytes(&tampered, &bytes);
+    assert_eq!(verify_archive(&pubkey, &tampered, false).0, 3);
+
+    // a payload that doesn't match its signature
+    let mut bytes = original.clone();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    write_bytes(&tampered, &bytes);
+    let (exit_code, output) = verify_archive(&pubkey, &tampered, true);
+    assert_eq!(exit_code, 4);
+    assert!(output.contains("\"status\": \"signature_mismatch\""));
+
+    // the wrong key
+    let (other_pk, _) = SeedFile::new().keypair();
+    assert_eq!(verify_archive(&other_pk.as_ref().to_base64(base64::STANDARD), &archive, false).0, 4);
+
+    // a correctly signed payload that isn't a tarball
+    let payload = b"this is not a tarball";
+    let signature = sign::sign_detached(payload, &sk);
+    let mut bytes = b"TURBOv01".to_vec();
+    bytes.extend_from_slice(signature.as_ref().to_base64(base64::STANDARD).as_bytes());
+    bytes.extend_from_slice(payload);
+    write_bytes(&tampered, &bytes);
+    assert_eq!(verify_archive(&pubkey, &tampered, false).0, 6);
+
+    // an archive that has expired, long ago
+    let expiring = tempdir.path().join("expiring.tsar");
+    write_bytes(&expiring, &signed_archive(&sk, "[archive]\nroles = [\"first\"]\ncreated = 100\nexpires = 200\n"));
+    let (exit_code, output) = verify_archive(&pubkey, &expiring, true);
+    assert_eq!(exit_code, 5);
+    assert!(output.contains("\"status\": \"expired\""));
+    assert!(output.contains("\"roles\": ["));
+}
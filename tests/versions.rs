@@ -0,0 +1,65 @@
+extern crate turboshell;
+extern crate rustc_serialize;
+extern crate sodiumoxide;
+extern crate tempdir;
+
+mod common;
+
+use rustc_serialize::base64;
+use rustc_serialize::base64::ToBase64;
+use turboshell::commands::{compile, CompileOptions, extract, run};
+use turboshell::metadata::Metadata;
+use turboshell::seedfile::SeedFile;
+use common::{copy_dir, path_string, resource};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+
+#[test]
+fn main() {
+    if ! sodiumoxide::init() {
+        panic!("Failed to init libsodium");
+    }
+
+    let tempdir = tempdir::TempDir::new("versions").unwrap();
+    let basedir = tempdir.path().join("code");
+    copy_dir(resource("integration_tests").join("versions"), &basedir);
+
+    let seed = SeedFile::new();
+    let seedfile = tempdir.path().join("seedfile");
+    fs::File::create(&seedfile).unwrap().write_all(seed.to_string().unwrap().as_bytes()).unwrap();
+    let (pk, _) = seed.keypair();
+    let pubkey = pk.as_ref().to_base64(base64::STANDARD);
+
+    let archive = tempdir.path().join("archive.tsar");
+    assert_eq!(compile(&mut io::Cursor::new(Vec::new()),
+                       CompileOptions{basedir: path_string(&basedir),
+                                      output: path_string(&archive),
+                                      seedfile: path_string(&seedfile),
+                                      roles: vec!["web".to_string()],
+                                      ..Default::default()}),
+               0);
+
+    ///////////////////////////////////////////////////
+    // only the version that was picked is archived //
+    ///////////////////////////////////////////////////
+    let extracted = tempdir.path().join("extracted");
+    assert_eq!(extract(&mut io::Cursor::new(Vec::new()), false, pubkey.clone(), path_string(&archive), path_string(&extracted)), 0);
+    assert!(extracted.join("nginx").join("1.0.0").join("main.sh").is_file());
+    assert!(!extracted.join("nginx").join("2.0.0").exists());
+
+    // and archive.toml records it
+    let mut archive_toml = String::new();
+    fs::File::open(extracted.join("archive.toml")).unwrap().read_to_string(&mut archive_toml).unwrap();
+    let metadata = Metadata::from_str("archive.toml", &archive_toml).unwrap();
+    assert_eq!(metadata.role_packages["web"]["app"], "1.0.0");
+
+    /////////////////////
+    // run the archive //
+    /////////////////////
+    let mut output = io::Cursor::new(Vec::new());
+    assert_eq!(run(&mut output, "".to_string(), pubkey.clone(), path_string(&archive), "".to_string()), 0);
+    let output = String::from_utf8(output.into_inner()).unwrap();
+    assert!(output.starts_with("Running: nginx, app\n"));
+    assert!(output.contains("nginx 1.0.0\n"));
+}
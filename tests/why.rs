@@ -0,0 +1,29 @@
+extern crate turboshell;
+
+mod common;
+
+use turboshell::commands::why;
+use common::{path_string, resource};
+use std::io;
+
+#[test]
+fn main() {
+    let why_string = |roles: &str, package: &str| {
+        let mut output = io::Cursor::new(Vec::new());
+        let code = why(&mut output, path_string(resource("integration_tests").join("graph")), roles.to_string(), package.to_string());
+        (code, String::from_utf8(output.into_inner()).unwrap())
+    };
+
+    ////////////////////////////////////////
+    // every path from the roles, in full //
+    ////////////////////////////////////////
+    assert_eq!(why_string("web", "db"), (0, "role web -> app -> db\n".to_string()));
+    assert_eq!(why_string("web", "cache"), (0, "role web -> app -> cache (when memory >= 2G)\n".to_string()));
+    assert_eq!(why_string("web", "app"), (0, "role web -> app\n".to_string()));
+
+    /////////////////////////////////////////////
+    // packages the roles don't use are errors //
+    /////////////////////////////////////////////
+    assert_eq!(why_string("web", "nginx").0, 1);
+    assert_eq!(why_string("nope", "db").0, 1);
+}